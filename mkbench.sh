@@ -0,0 +1,21 @@
+#!/bin/sh
+
+# Generate the file for the "very many matches" benchmarks in test.sh:
+# 1M lines of words from a fixed list, picked with a simple LCG so that the
+# output is the same on every run.  Every 10th line is empty.
+
+OUT="$1"
+if [ -z "$OUT" ]; then OUT=many-matches.txt; fi
+awk 'BEGIN {
+  n = split("alpha beta gamma delta epsilon zeta eta theta iota kappa lambda mu", words, " ")
+  seed = 42
+  for (line = 1; line <= 1000000; line++) {
+    if (line % 10 == 0) { print ""; continue }
+    s = ""
+    for (w = 0; w < 8; w++) {
+      seed = (seed * 69069 + 1) % 4294967296
+      s = s (w ? " " : "") words[int(seed / 65536) % n + 1]
+    }
+    print s
+  }
+}' > "$OUT"
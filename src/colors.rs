@@ -0,0 +1,252 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::cmp::min;
+
+/// Contains the ANSI codes needed to set the terminal to a certain color.
+#[derive(Clone)]
+pub struct Colors {
+    pub reset: Vec<u8>,
+    pub path: Vec<u8>,
+    pub lineno: Vec<u8>,
+    pub column: Vec<u8>,
+    pub span: Vec<u8>,
+    pub context: Vec<u8>,
+    pub punct: Vec<u8>,
+    pub empty: bool,
+}
+
+impl Colors {
+    /// Create a struct where no colors are emitted.
+    pub fn empty() -> Colors {
+        Colors {
+            reset: vec![],
+            path: vec![],
+            lineno: vec![],
+            column: vec![],
+            span: vec![],
+            context: vec![],
+            punct: vec![],
+            empty: true,
+        }
+    }
+}
+
+/// The style for one kind of output, as SGR parameters.
+#[derive(Clone, Default)]
+struct Style {
+    fg: Option<String>,
+    bg: Option<String>,
+    attrs: Vec<String>,
+}
+
+impl Style {
+    /// Split raw SGR parameters into colors and attributes, so that a color
+    /// given later replaces the color from here.
+    fn from_sgr(sgr: &str) -> Style {
+        let mut style = Style::default();
+        let params = sgr.split(';').filter(|p| !p.is_empty()).collect::<Vec<_>>();
+        let mut i = 0;
+        while i < params.len() {
+            let n = params[i].parse::<u8>().unwrap_or(0);
+            // extended colors take 2 or 4 more parameters
+            let len = match (n, params.get(i + 1)) {
+                (38, Some(&"5")) | (48, Some(&"5")) => 3,
+                (38, Some(&"2")) | (48, Some(&"2")) => 5,
+                _ => 1,
+            };
+            let param = params[i..min(i + len, params.len())].join(";");
+            match n {
+                30...39 | 90...97 => style.fg = Some(param),
+                40...49 | 100...107 => style.bg = Some(param),
+                _ => style.attrs.push(param),
+            }
+            i += len;
+        }
+        style
+    }
+
+    /// Get the ANSI sequence to switch to this style.
+    fn escape(&self) -> Vec<u8> {
+        let params = self.attrs.iter().chain(&self.fg).chain(&self.bg)
+                                      .filter(|p| !p.is_empty())
+                                      .cloned().collect::<Vec<_>>();
+        if params.is_empty() {
+            vec![]
+        } else {
+            format!("\x1b[{}m", params.join(";")).into_bytes()
+        }
+    }
+}
+
+/// Check that a raw color spec (the payload of the ANSI sequence, e.g.
+/// "01;31") is made of valid SGR parameters.
+fn check_sgr(sgr: &str) -> Result<(), String> {
+    if sgr.split(';').all(|p| p.is_empty() || p.parse::<u8>().is_ok()) {
+        Ok(())
+    } else {
+        Err(format!("invalid ANSI color sequence: {}", sgr))
+    }
+}
+
+/// Parse a color value: a name, a number from the 256-color palette or a
+/// "r,g,b" triple.  `base` is 30 for foreground and 40 for background.
+fn parse_color(value: &str, base: u8) -> Result<String, String> {
+    let names = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+    if let Some(n) = names.iter().position(|&name| name == value) {
+        return Ok(format!("{}", base + n as u8));
+    }
+    if let Ok(n) = value.parse::<u8>() {
+        return Ok(format!("{};5;{}", base + 8, n));
+    }
+    let rgb = value.split(',').map(|c| c.trim().parse::<u8>()).collect::<Vec<_>>();
+    if rgb.len() == 3 && rgb.iter().all(|c| c.is_ok()) {
+        let rgb = rgb.into_iter().map(|c| format!("{}", c.unwrap())).collect::<Vec<_>>();
+        return Ok(format!("{};2;{}", base + 8, rgb.join(";")));
+    }
+    Err(format!("invalid color: {} (use a name, 0-255 or r,g,b)", value))
+}
+
+/// Parse a text style attribute.
+fn parse_attr(value: &str) -> Result<&'static str, String> {
+    Ok(match value {
+        "bold" | "intense" => "1",
+        "nobold" | "nointense" => "22",
+        "italic" => "3",
+        "noitalic" => "23",
+        "underline" => "4",
+        "nounderline" => "24",
+        "reverse" => "7",
+        "noreverse" => "27",
+        _ => return Err(format!("invalid style: {}", value)),
+    })
+}
+
+/// The colors for all kinds of output, as given by defaults and the user.
+pub struct ColorSpecs {
+    path: Style,
+    line: Style,
+    column: Style,
+    match_: Style,
+    context: Style,
+    separator: Style,
+}
+
+impl ColorSpecs {
+    /// Create the default color specs.
+    pub fn new() -> ColorSpecs {
+        ColorSpecs {
+            path: Style::from_sgr("35"),
+            line: Style::from_sgr("32"),
+            column: Style::from_sgr("32"),
+            match_: Style::from_sgr("4"),
+            context: Style::default(),
+            separator: Style::from_sgr("36"),
+        }
+    }
+
+    fn style_mut(&mut self, kind: &str) -> Result<&mut Style, String> {
+        Ok(match kind {
+            "path" => &mut self.path,
+            "line" => &mut self.line,
+            "column" => &mut self.column,
+            "match" => &mut self.match_,
+            "context" => &mut self.context,
+            "separator" => &mut self.separator,
+            _ => return Err(format!("invalid color type: {} (use path, line, column, \
+                                     match, context or separator)", kind)),
+        })
+    }
+
+    /// Replace the style of an output kind by a raw ANSI spec, e.g. "01;31".
+    pub fn set_raw(&mut self, kind: &str, sgr: &str) -> Result<(), String> {
+        try!(check_sgr(sgr));
+        *try!(self.style_mut(kind)) = Style::from_sgr(sgr);
+        Ok(())
+    }
+
+    /// Apply a spec of the form "type:attribute:value", where attribute is
+    /// "fg", "bg" or "style", or of the form "type:none" to reset the style.
+    pub fn apply_spec(&mut self, spec: &str) -> Result<(), String> {
+        let mut parts = spec.splitn(3, ':');
+        let style = try!(self.style_mut(parts.next().unwrap()));
+        match (parts.next(), parts.next()) {
+            (Some("none"), None) => *style = Style::default(),
+            (Some("fg"), Some(value)) => style.fg = Some(try!(parse_color(value, 30))),
+            (Some("bg"), Some(value)) => style.bg = Some(try!(parse_color(value, 40))),
+            (Some("style"), Some("none")) => style.attrs.clear(),
+            (Some("style"), Some(value)) => style.attrs.push(try!(parse_attr(value)).into()),
+            _ => return Err(format!("invalid color spec: {} (use type:fg:color, \
+                                     type:bg:color, type:style:style or type:none)", spec)),
+        }
+        Ok(())
+    }
+
+    /// Apply the specs from a GREP_COLORS value, such as "ms=01;31:fn=35".
+    /// Capabilities that have no counterpart here are ignored.
+    pub fn apply_grep_colors(&mut self, value: &str) -> Result<(), String> {
+        for cap in value.split(':').filter(|cap| !cap.is_empty()) {
+            let mut parts = cap.splitn(2, '=');
+            let kind = match parts.next().unwrap() {
+                "fn" => "path",
+                "ln" => "line",
+                "bn" => "column",
+                "mt" | "ms" => "match",
+                "cx" => "context",
+                "se" => "separator",
+                _ => continue,
+            };
+            if let Some(sgr) = parts.next() {
+                try!(self.set_raw(kind, sgr).map_err(|e| format!("in GREP_COLORS: {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the Colors struct with the escape sequences.
+    pub fn to_colors(&self) -> Colors {
+        Colors {
+            reset: b"\x1b[0m".to_vec(),
+            path: self.path.escape(),
+            lineno: self.line.escape(),
+            column: self.column.escape(),
+            span: self.match_.escape(),
+            context: self.context.escape(),
+            punct: self.separator.escape(),
+            empty: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColorSpecs, Style};
+
+    fn parts(style: &Style) -> (Option<&str>, Option<&str>, Vec<&str>) {
+        (style.fg.as_ref().map(|s| &s[..]), style.bg.as_ref().map(|s| &s[..]),
+         style.attrs.iter().map(|s| &s[..]).collect())
+    }
+
+    #[test]
+    fn sgr_split() {
+        assert_eq!(parts(&Style::from_sgr("01;31")), (Some("31"), None, vec!["01"]));
+        assert_eq!(parts(&Style::from_sgr("4;38;5;208;48;2;1;2;3")),
+                   (Some("38;5;208"), Some("48;2;1;2;3"), vec!["4"]));
+        assert_eq!(parts(&Style::from_sgr("")), (None, None, vec![]));
+    }
+
+    #[test]
+    fn override_default() {
+        let mut specs = ColorSpecs::new();
+        specs.apply_spec("path:fg:red").unwrap();
+        specs.apply_spec("path:style:bold").unwrap();
+        assert_eq!(specs.to_colors().path, b"\x1b[1;31m".to_vec());
+        specs.apply_grep_colors("fn=01;35:bn=33").unwrap();
+        specs.apply_spec("path:fg:blue").unwrap();
+        let colors = specs.to_colors();
+        assert_eq!(colors.path, b"\x1b[01;34m".to_vec());
+        assert_eq!(colors.column, b"\x1b[33m".to_vec());
+    }
+}
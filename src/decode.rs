@@ -0,0 +1,86 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::borrow::Cow;
+use std::char;
+
+use encoding::{DecoderTrap, EncodingRef};
+use encoding::all::{UTF_16BE, UTF_16LE};
+
+/// Text encodings that can be announced by a byte order mark.
+#[derive(Debug, PartialEq)]
+enum Bom {
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+}
+
+/// Detect a byte order mark at the start of the buffer.
+///
+/// Returns the encoding and the length of the BOM.  The UTF-8 BOM is not
+/// reported since UTF-8 files need no transcoding.
+fn sniff_bom(buf: &[u8]) -> Option<(Bom, usize)> {
+    // the UTF-32LE BOM starts with the UTF-16LE one, so check it first
+    if buf.starts_with(b"\xFF\xFE\x00\x00") {
+        Some((Bom::Utf32Le, 4))
+    } else if buf.starts_with(b"\x00\x00\xFE\xFF") {
+        Some((Bom::Utf32Be, 4))
+    } else if buf.starts_with(b"\xFF\xFE") {
+        Some((Bom::Utf16Le, 2))
+    } else if buf.starts_with(b"\xFE\xFF") {
+        Some((Bom::Utf16Be, 2))
+    } else {
+        None
+    }
+}
+
+/// Decode UTF-32 (which the encoding crate does not support) into a String.
+///
+/// Invalid code points and a trailing partial code unit are replaced by U+FFFD.
+fn decode_utf32(buf: &[u8], little_endian: bool) -> String {
+    let mut result = String::with_capacity(buf.len() / 4);
+    for unit in buf.chunks(4) {
+        if unit.len() < 4 {
+            result.push(char::REPLACEMENT_CHARACTER);
+            break;
+        }
+        let cp = if little_endian {
+            (unit[0] as u32) | (unit[1] as u32) << 8 | (unit[2] as u32) << 16 | (unit[3] as u32) << 24
+        } else {
+            (unit[3] as u32) | (unit[2] as u32) << 8 | (unit[1] as u32) << 16 | (unit[0] as u32) << 24
+        };
+        result.push(char::from_u32(cp).unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+    result
+}
+
+/// Decode with an encoding from the encoding crate, replacing invalid input.
+fn decode_with(buf: &[u8], encoding: EncodingRef) -> String {
+    // with DecoderTrap::Replace, decoding never fails
+    encoding.decode(buf, DecoderTrap::Replace).unwrap_or_default()
+}
+
+/// Transcode a file buffer to UTF-8, which is what the regex engines match on.
+///
+/// A byte order mark for UTF-16 or UTF-32 always determines the encoding.
+/// Otherwise, the given encoding (from the command line) is used, if any.
+/// Buffers that need no transcoding are returned unchanged.
+pub fn transcode<'a>(buf: &'a [u8], encoding: Option<EncodingRef>) -> Cow<'a, [u8]> {
+    if let Some((bom, bom_len)) = sniff_bom(buf) {
+        let text = &buf[bom_len..];
+        let decoded = match bom {
+            Bom::Utf16Le => decode_with(text, UTF_16LE),
+            Bom::Utf16Be => decode_with(text, UTF_16BE),
+            Bom::Utf32Le => decode_utf32(text, true),
+            Bom::Utf32Be => decode_utf32(text, false),
+        };
+        return Cow::Owned(decoded.into_bytes());
+    }
+    match encoding {
+        Some(enc) if enc.name() != "utf-8" => Cow::Owned(decode_with(buf, enc).into_bytes()),
+        _ => Cow::Borrowed(buf),
+    }
+}
@@ -3,33 +3,153 @@
 // Licensed under the MIT license.
 // ---------------------------------------------------------------------------------------
 
-use std::io::Write;
+use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::io::{self, Write};
 use std::usize;
 
-use search::{FileResult, Match};
-use options::Colors;
+use search::{FileResult, Match, HEX_ROW_LEN};
+use colors::Colors;
+use hyperlink::Hyperlinks;
+use stats::Stats;
 
 macro_rules! w {
     ($out:expr, $first:expr, $($rest:expr),*) => {
-        let _ = $out.write($first);
+        try!($out.write_all($first));
         w!($out, $($rest),*);
     };
     ($out:expr, $first:expr) => {
-        let _ = $out.write($first);
+        try!($out.write_all($first));
     }
 }
 
-fn w_maybe_nl<T: Write>(out: &mut T, line: &[u8]) {
+fn w_maybe_nl<T: Write>(out: &mut T, line: &[u8]) -> io::Result<()> {
     w!(out, line);
     if !line.ends_with(b"\n") {
         w!(out, b"\n");
     }
+    Ok(())
+}
+
+/// Helper: print a colored file name, linked to the file if `links` are enabled.
+fn w_fname_link<T: Write>(out: &mut T, colors: &Colors, links: &mut Option<Hyperlinks>,
+                          fname: &str) -> io::Result<()> {
+    if let Some(ref mut links) = *links {
+        try!(links.open(out, fname, None, None));
+        w!(out, &colors.path, fname.as_bytes(), &colors.reset);
+        try!(links.close(out));
+    } else {
+        w!(out, &colors.path, fname.as_bytes(), &colors.reset);
+    }
+    Ok(())
+}
+
+/// Helper: print a colored file name followed by the separator `sep`, which is
+/// replaced by a NUL byte if file names should be NUL-terminated.
+fn w_fname<T: Write>(out: &mut T, colors: &Colors, links: &mut Option<Hyperlinks>,
+                     fname: &str, sep: &[u8], null: bool) -> io::Result<()> {
+    try!(w_fname_link(out, colors, links, fname));
+    if null {
+        w!(out, b"\x00");
+    } else {
+        w!(out, &colors.punct, sep, &colors.reset);
+    }
+    Ok(())
+}
+
+/// The part of a line to print when lines are limited by --max-columns.
+enum Excerpt {
+    /// The whole line
+    Full,
+    /// Nothing, print a placeholder
+    Omitted,
+    /// The given range of bytes
+    Window(usize, usize),
+}
+
+/// How to print lines that are longer than --max-columns.
+#[derive(Clone, Copy)]
+pub struct LineLimit {
+    max: Option<usize>,
+    preview: bool,
+}
+
+impl LineLimit {
+    /// Create a limit of `max` bytes per line.  If `preview` is true, a window
+    /// around the first match is printed instead of omitting the line.
+    pub fn new(max: Option<usize>, preview: bool) -> LineLimit {
+        LineLimit { max: max, preview: preview }
+    }
+
+    /// Determine what to print of `line` (which might end in a newline).
+    fn excerpt(&self, line: &[u8], spans: &[(usize, usize)]) -> Excerpt {
+        let limit = match self.max {
+            Some(limit) => limit,
+            None => return Excerpt::Full,
+        };
+        let len = if line.ends_with(b"\n") { line.len() - 1 } else { line.len() };
+        if len <= limit {
+            return Excerpt::Full;
+        }
+        if !self.preview {
+            return Excerpt::Omitted;
+        }
+        // center the window on the first match, but keep it inside the line
+        let (mstart, mend) = spans.first().cloned().unwrap_or((0, 0));
+        let mut start = if mend - mstart >= limit {
+            mstart
+        } else {
+            min((mstart + mend).saturating_sub(limit) / 2, len - limit)
+        };
+        let mut end = start + limit;
+        // don't cut UTF-8 sequences
+        while start < end && line[start] & 0xC0 == 0x80 {
+            start += 1;
+        }
+        while end > start && end < len && line[end] & 0xC0 == 0x80 {
+            end -= 1;
+        }
+        Excerpt::Window(start, end)
+    }
+
+    /// Get the line as it should be printed without colors.
+    fn shorten<'a>(&self, line: &'a [u8], spans: &[(usize, usize)]) -> Cow<'a, [u8]> {
+        match self.excerpt(line, spans) {
+            Excerpt::Full => Cow::Borrowed(line),
+            Excerpt::Omitted => Cow::Owned(omitted_message(spans.len())),
+            Excerpt::Window(start, end) => {
+                let len = if line.ends_with(b"\n") { line.len() - 1 } else { line.len() };
+                let mut result = Vec::with_capacity(end - start + 7);
+                if start > 0 {
+                    result.extend_from_slice(b"...");
+                }
+                result.extend_from_slice(&line[start..end]);
+                if end < len {
+                    result.extend_from_slice(b"...");
+                }
+                result.push(b'\n');
+                Cow::Owned(result)
+            }
+        }
+    }
+}
+
+/// The placeholder for an omitted long line with `n` matches.
+fn omitted_message(n: usize) -> Vec<u8> {
+    match n {
+        0 => format!("[omitted long line]\n"),
+        1 => format!("[omitted long line with 1 match]\n"),
+        n => format!("[omitted long line with {} matches]\n", n),
+    }.into_bytes()
 }
 
 /// A trait for printing search results to stdout.
 pub trait DisplayMode {
     /// Print results from a single file.
-    fn print_result(&mut self, res: FileResult);
+    fn print_result(&mut self, res: FileResult) -> io::Result<()>;
+    /// Print the statistics after all results.  Modes whose output is meant
+    /// for other programs print them to stderr.
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()>;
 }
 
 /// The default mode, used when printing to tty stdout.
@@ -37,57 +157,134 @@ pub trait DisplayMode {
 /// Uses grouping by file names by default and can use colors.  Can print context.
 pub struct DefaultMode<T: Write> {
     colors: Colors,
+    links: Option<Hyperlinks>,
+    limit: LineLimit,
     grouping: bool,
     heading: bool,
+    null: bool,
     is_first: bool,
     out: T,
 }
 
 impl<T: Write> DefaultMode<T> {
-    pub fn new(out: T, colors: Colors, grouping: bool, heading: bool) -> DefaultMode<T> {
+    pub fn new(out: T, colors: Colors, links: Option<Hyperlinks>, limit: LineLimit,
+               grouping: bool, heading: bool, null: bool) -> DefaultMode<T> {
         DefaultMode {
             colors: colors,
+            links: links,
+            limit: limit,
             grouping: grouping,
             heading: heading,
+            null: null,
             is_first: true,
             out: out,
         }
     }
 
-    fn print_separator(&mut self) {
+    fn print_separator(&mut self) -> io::Result<()> {
         w!(self.out, &self.colors.punct, b"--", &self.colors.reset, b"\n");
+        Ok(())
+    }
+
+    /// Helper: print the grouping separator between files.
+    fn print_file_separator(&mut self, res: &FileResult) -> io::Result<()> {
+        // not on the first file
+        if !self.is_first && self.grouping {
+            w!(self.out, b"\n");
+            if res.has_context && !self.heading {
+                // in context mode, we have to print a "--" separator between files
+                try!(self.print_separator());
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper: print a colored line number followed by the separator `sep`.  The
+    /// line number is linked to the line (and column, if known).
+    fn print_lineno(&mut self, fname: &str, lineno: usize, column: Option<usize>,
+                    sep: &[u8]) -> io::Result<()> {
+        if let Some(ref mut links) = self.links {
+            try!(links.open(&mut self.out, fname, Some(lineno), column));
+        }
+        w!(self.out, &self.colors.lineno, format!("{}", lineno).as_bytes(), &self.colors.reset);
+        if let Some(ref links) = self.links {
+            try!(links.close(&mut self.out));
+        }
+        w!(self.out, &self.colors.punct, sep, &self.colors.reset);
+        Ok(())
+    }
+
+    /// Helper: print a context line.
+    fn print_context_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = self.limit.shorten(line, &[]);
+        if self.colors.context.is_empty() {
+            w_maybe_nl(&mut self.out, &line)
+        } else {
+            let content = if line.ends_with(b"\n") { &line[..line.len() - 1] } else { &line };
+            w!(self.out, &self.colors.context, content, &self.colors.reset, b"\n");
+            Ok(())
+        }
     }
 
     /// Helper: print a line with matched spans highlighted.
-    fn print_line_with_spans(&mut self, m: &Match) {
+    fn print_line_with_spans(&mut self, m: &Match) -> io::Result<()> {
+        let spans = m.all_spans();
         if self.colors.empty {
-            w_maybe_nl(&mut self.out, &m.line);
-        } else {
-            let mut pos = 0;
-            for &(start, end) in &m.spans {
-                if start > pos {
-                    w!(self.out, &m.line[pos..start]);
-                }
-                w!(self.out, &self.colors.span, &m.line[start..end], &self.colors.reset);
-                pos = end;
+            return w_maybe_nl(&mut self.out, &self.limit.shorten(&m.line, &spans));
+        }
+        let (start, end) = match self.limit.excerpt(&m.line, &spans) {
+            Excerpt::Full => (0, m.line.len()),
+            Excerpt::Omitted => {
+                let msg = omitted_message(spans.len());
+                w!(self.out, &self.colors.punct, &msg[..msg.len() - 1], &self.colors.reset,
+                   b"\n");
+                return Ok(());
+            }
+            Excerpt::Window(start, end) => (start, end),
+        };
+        if start > 0 {
+            w!(self.out, &self.colors.punct, b"...", &self.colors.reset);
+        }
+        let mut pos = start;
+        for &(sstart, send) in spans.iter() {
+            // only the part of the span inside the window
+            let (sstart, send) = (max(sstart, pos), min(send, end));
+            if sstart >= send {
+                continue;
             }
-            w_maybe_nl(&mut self.out, &m.line[pos..]);
+            if sstart > pos {
+                w!(self.out, &m.line[pos..sstart]);
+            }
+            w!(self.out, &self.colors.span, &m.line[sstart..send], &self.colors.reset);
+            pos = send;
+        }
+        if end < m.line.len() {
+            w!(self.out, &m.line[pos..end]);
+            if !m.line[end..].starts_with(b"\n") {
+                w!(self.out, &self.colors.punct, b"...", &self.colors.reset);
+            }
+            w!(self.out, b"\n");
+            Ok(())
+        } else {
+            w_maybe_nl(&mut self.out, &m.line[pos..])
         }
     }
 
     /// Helper: print a match with custom callbacks for file header and match line.
-    fn match_printer<FF, LF>(&mut self, res: &FileResult, file_func: FF, line_func: LF)
-        where FF: Fn(&mut Self, &FileResult), LF: Fn(&mut Self, &FileResult, usize, &'static [u8])
+    fn match_printer<FF, LF>(&mut self, res: &FileResult, file_func: FF,
+                             line_func: LF) -> io::Result<()>
+        where FF: Fn(&mut Self, &FileResult) -> io::Result<()>,
+              LF: Fn(&mut Self, &FileResult, usize, Option<usize>, &'static [u8]) -> io::Result<()>
     {
         // (maybe) print a heading for the whole file
-        file_func(self, &res);
+        try!(file_func(self, &res));
         // easy case without context lines
         if !res.has_context {
             for m in &res.matches {
-                line_func(self, res, m.lineno, b":");
-                self.print_line_with_spans(&m);
+                try!(line_func(self, res, m.lineno, m.all_spans().first().map(|s| s.0 + 1), b":"));
+                try!(self.print_line_with_spans(&m));
             }
-            return;
+            return Ok(());
         }
         // remember the last printed line: to be able to print "--" separators
         // between non-consecutive lines in context mode
@@ -97,21 +294,21 @@ impl<T: Write> DefaultMode<T> {
             for (i, line) in m.before.iter().enumerate() {
                 let lno = m.lineno - m.before.len() + i;
                 if last_printed_line > 0 && lno > last_printed_line + 1 {
-                    self.print_separator();
+                    try!(self.print_separator());
                 }
                 // only print this line if we didn't print it before, e.g.
                 // as a match line or after-context line
                 if lno > last_printed_line {
-                    line_func(self, res, lno, b"-");
-                    w_maybe_nl(&mut self.out, &line);
+                    try!(line_func(self, res, lno, None, b"-"));
+                    try!(self.print_context_line(&line));
                     last_printed_line = lno;
                 }
             }
             if last_printed_line > 0 && m.lineno > last_printed_line + 1 {
-                self.print_separator();
+                try!(self.print_separator());
             }
-            line_func(self, res, m.lineno, b":");
-            self.print_line_with_spans(&m);
+            try!(line_func(self, res, m.lineno, m.all_spans().first().map(|s| s.0 + 1), b":"));
+            try!(self.print_line_with_spans(&m));
             // print after-context
             last_printed_line = m.lineno;
             // determine line number of next match, since we have to stop
@@ -127,67 +324,171 @@ impl<T: Write> DefaultMode<T> {
                 if lno >= next_match_line {
                     break;
                 }
-                line_func(self, res, lno, b"-");
-                w_maybe_nl(&mut self.out, &line);
+                try!(line_func(self, res, lno, None, b"-"));
+                try!(self.print_context_line(&line));
                 last_printed_line = lno;
             }
         }
+        Ok(())
     }
 }
 
 impl<T: Write> DisplayMode for DefaultMode<T> {
 
-    fn print_result(&mut self, res: FileResult) {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
         // files with no matches never print anything
         if res.matches.is_empty() {
-            return;
-        }
-        // grouping separator, but not on the first file
-        if !self.is_first && self.grouping {
-            w!(self.out, b"\n");
-            if res.has_context && !self.heading {
-                // in context mode, we have to print a "--" separator between files
-                self.print_separator();
-            }
+            return Ok(());
         }
+        try!(self.print_file_separator(&res));
         if res.is_binary {
             // special message for binary files
             w!(self.out, b"Binary file ", res.fname.as_bytes(), b" matches.\n");
         } else if self.heading {
             // headings mode: print file name first, then omit it from match lines
-            self.match_printer(&res, |slf, res| {
-                w!(slf.out,
-                   &slf.colors.path, res.fname.as_bytes(), &slf.colors.reset, b"\n");
-            }, |slf, _, lineno, sep| {
-                w!(slf.out,
-                   &slf.colors.lineno, format!("{}", lineno).as_bytes(), &slf.colors.reset,
-                   &slf.colors.punct, sep, &slf.colors.reset);
-            });
+            try!(self.match_printer(&res, |slf, res| {
+                w_fname(&mut slf.out, &slf.colors, &mut slf.links, &res.fname, b"\n", slf.null)
+            }, |slf, res, lineno, column, sep| {
+                slf.print_lineno(&res.fname, lineno, column, sep)
+            }));
         } else {
             // no headings mode: print file name on every match line
-            self.match_printer(&res, |_, _| { }, |slf, res, lineno, sep| {
-                w!(slf.out,
-                   &slf.colors.path, res.fname.as_bytes(), &slf.colors.reset,
-                   &slf.colors.punct, sep, &slf.colors.reset,
-                   &slf.colors.lineno, format!("{}", lineno).as_bytes(), &slf.colors.reset,
-                   &slf.colors.punct, sep, &slf.colors.reset);
-            });
+            try!(self.match_printer(&res, |_, _| Ok(()), |slf, res, lineno, column, sep| {
+                try!(w_fname(&mut slf.out, &slf.colors, &mut slf.links, &res.fname, sep,
+                             slf.null));
+                slf.print_lineno(&res.fname, lineno, column, sep)
+            }));
         }
         self.is_first = false;
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        // file names terminated by NUL bytes are for other programs
+        if self.null {
+            return stats.write_to(&mut io::stderr());
+        }
+        if !self.is_first {
+            w!(self.out, b"\n");
+        }
+        stats.write_to(&mut self.out)
+    }
+}
+
+/// The mode used for --hexdump mode.
+///
+/// Like the default mode, but matches in binary files are printed with their
+/// byte offset and a hexdump of the surrounding bytes.
+pub struct HexDumpMode<T: Write> {
+    inner: DefaultMode<T>,
+}
+
+impl<T: Write> HexDumpMode<T> {
+    pub fn new(out: T, colors: Colors, links: Option<Hyperlinks>, limit: LineLimit,
+               grouping: bool, heading: bool, null: bool) -> HexDumpMode<T> {
+        HexDumpMode {
+            inner: DefaultMode::new(out, colors, links, limit, grouping, heading, null),
+        }
+    }
+
+    /// Helper: print one row of a hexdump, with matched bytes highlighted.
+    fn print_row(&mut self, offset: usize, row: &[u8], span: (usize, usize)) -> io::Result<()> {
+        let slf = &mut self.inner;
+        w!(slf.out, &slf.colors.column, format!("{:08x}", offset).as_bytes(),
+           &slf.colors.reset, b" ");
+        for i in 0..HEX_ROW_LEN {
+            if i == HEX_ROW_LEN / 2 {
+                w!(slf.out, b" ");
+            }
+            if i >= row.len() {
+                w!(slf.out, b"   ");
+            } else if i >= span.0 && i < span.1 {
+                w!(slf.out, b" ", &slf.colors.span, format!("{:02x}", row[i]).as_bytes(),
+                   &slf.colors.reset);
+            } else {
+                w!(slf.out, format!(" {:02x}", row[i]).as_bytes());
+            }
+        }
+        w!(slf.out, b"  ", &slf.colors.punct, b"|", &slf.colors.reset);
+        for (i, &b) in row.iter().enumerate() {
+            let printable = if b >= 0x20 && b < 0x7F { b } else { b'.' };
+            if i >= span.0 && i < span.1 {
+                w!(slf.out, &slf.colors.span, &[printable], &slf.colors.reset);
+            } else {
+                w!(slf.out, &[printable]);
+            }
+        }
+        w!(slf.out, &slf.colors.punct, b"|", &slf.colors.reset, b"\n");
+        Ok(())
+    }
+}
+
+impl<T: Write> DisplayMode for HexDumpMode<T> {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
+        // text files are printed as usual
+        if !res.is_binary {
+            return self.inner.print_result(res);
+        }
+        if res.matches.is_empty() {
+            return Ok(());
+        }
+        // with --invert-match, there are no excerpts: just report the file
+        if res.matches[0].spans.is_empty() {
+            return self.inner.print_result(res);
+        }
+        try!(self.inner.print_file_separator(&res));
+        if self.inner.heading {
+            let slf = &mut self.inner;
+            try!(w_fname(&mut slf.out, &slf.colors, &mut slf.links, &res.fname, b"\n", slf.null));
+        }
+        for (im, m) in res.matches.iter().enumerate() {
+            if im > 0 {
+                try!(self.inner.print_separator());
+            }
+            // the match offset, in place of the line number
+            let (start, end) = m.spans[0];
+            {
+                let slf = &mut self.inner;
+                if !slf.heading {
+                    try!(w_fname(&mut slf.out, &slf.colors, &mut slf.links, &res.fname, b":",
+                                 slf.null));
+                }
+                w!(slf.out,
+                   &slf.colors.column, format!("0x{:x}", m.offset + start).as_bytes(),
+                   &slf.colors.reset, &slf.colors.punct, b":", &slf.colors.reset,
+                   format!(" {} bytes\n", end - start).as_bytes());
+            }
+            for (ir, row) in m.line.chunks(HEX_ROW_LEN).enumerate() {
+                let row_start = ir * HEX_ROW_LEN;
+                let span = (start.saturating_sub(row_start), end.saturating_sub(row_start));
+                try!(self.print_row(m.offset + row_start, row, span));
+            }
+        }
+        self.inner.is_first = false;
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        self.inner.print_stats(stats)
     }
 }
 
 /// The mode used for --ackmate mode.
 ///
 /// No colors, one matched line per line, all spans indicated numerically.
+/// Spans always refer to the full line, even if it is shortened for printing.
 pub struct AckMateMode<T: Write> {
+    limit: LineLimit,
+    null: bool,
     is_first: bool,
     out: T,
 }
 
 impl<T: Write> AckMateMode<T> {
-    pub fn new(out: T) -> AckMateMode<T> {
+    pub fn new(out: T, limit: LineLimit, null: bool) -> AckMateMode<T> {
         AckMateMode {
+            limit: limit,
+            null: null,
             is_first: true,
             out: out,
         }
@@ -195,9 +496,9 @@ impl<T: Write> AckMateMode<T> {
 }
 
 impl<T: Write> DisplayMode for AckMateMode<T> {
-    fn print_result(&mut self, res: FileResult) {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
         if res.matches.is_empty() {
-            return;
+            return Ok(());
         }
         if !self.is_first {
             w!(self.out, b"\n");
@@ -205,16 +506,22 @@ impl<T: Write> DisplayMode for AckMateMode<T> {
         if res.is_binary {
             w!(self.out, b"Binary file ", res.fname.as_bytes(), b" matches.\n");
         } else {
-            w!(self.out, b":", res.fname.as_bytes());
+            w!(self.out, b":", res.fname.as_bytes(), if self.null { b"\x00" } else { b"\n" });
             for m in res.matches {
-                let spans = m.spans.iter()
-                                   .map(|&(s, e)| format!("{} {}", s, e - s))
-                                   .collect::<Vec<_>>().join(",");
-                w!(self.out, &format!("{};{}:", m.lineno, spans).as_bytes());
-                w_maybe_nl(&mut self.out, &m.line);
+                let spans = m.all_spans();
+                let text = spans.iter()
+                                .map(|&(s, e)| format!("{} {}", s, e - s))
+                                .collect::<Vec<_>>().join(",");
+                w!(self.out, &format!("{};{}:", m.lineno, text).as_bytes());
+                try!(w_maybe_nl(&mut self.out, &self.limit.shorten(&m.line, &spans)));
             }
         }
         self.is_first = false;
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        stats.write_to(&mut io::stderr())
     }
 }
 
@@ -223,32 +530,44 @@ impl<T: Write> DisplayMode for AckMateMode<T> {
 /// No colors, one match per line (so lines with multiple matches are printed
 /// multiple times).
 pub struct VimGrepMode<T: Write> {
+    limit: LineLimit,
+    null: bool,
     out: T,
 }
 
 impl<T: Write> VimGrepMode<T> {
-    pub fn new(out: T) -> VimGrepMode<T> {
+    pub fn new(out: T, limit: LineLimit, null: bool) -> VimGrepMode<T> {
         VimGrepMode {
+            limit: limit,
+            null: null,
             out: out,
         }
     }
 }
 
 impl<T: Write> DisplayMode for VimGrepMode<T> {
-    fn print_result(&mut self, res: FileResult) {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
         if res.matches.is_empty() {
-            return;
+            return Ok(());
         }
         if res.is_binary {
-            println!("Binary file {} matches.", res.fname);
+            w!(self.out, b"Binary file ", res.fname.as_bytes(), b" matches.\n");
         } else {
             for m in res.matches {
-                for s in &m.spans {
-                    w!(self.out, &format!("{}:{}:{}:", res.fname, m.lineno, s.0 + 1).as_bytes());
-                    w_maybe_nl(&mut self.out, &m.line);
+                let spans = m.all_spans();
+                let line = self.limit.shorten(&m.line, &spans);
+                for s in spans.iter() {
+                    w!(self.out, res.fname.as_bytes(), if self.null { b"\x00" } else { b":" },
+                       &format!("{}:{}:", m.lineno, s.0 + 1).as_bytes());
+                    try!(w_maybe_nl(&mut self.out, &line));
                 }
             }
         }
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        stats.write_to(&mut io::stderr())
     }
 }
 
@@ -257,25 +576,36 @@ impl<T: Write> DisplayMode for VimGrepMode<T> {
 /// One file per line, no contents printed.
 pub struct FilesOnlyMode<T: Write> {
     colors: Colors,
+    links: Option<Hyperlinks>,
     need_match: bool,
+    null: bool,
     out: T,
 }
 
 impl<T: Write> FilesOnlyMode<T> {
-    pub fn new(out: T, colors: Colors, need_match: bool) -> FilesOnlyMode<T> {
+    pub fn new(out: T, colors: Colors, links: Option<Hyperlinks>, need_match: bool,
+               null: bool) -> FilesOnlyMode<T> {
         FilesOnlyMode {
             colors: colors,
+            links: links,
             need_match: need_match,
+            null: null,
             out: out,
         }
     }
 }
 
 impl<T: Write> DisplayMode for FilesOnlyMode<T> {
-    fn print_result(&mut self, res: FileResult) {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
         if res.matches.is_empty() != self.need_match {
-            w!(self.out, &self.colors.path, &res.fname.as_bytes(), &self.colors.reset, b"\n");
+            try!(w_fname_link(&mut self.out, &self.colors, &mut self.links, &res.fname));
+            w!(self.out, if self.null { b"\x00" } else { b"\n" });
         }
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        stats.write_to(&mut io::stderr())
     }
 }
 
@@ -284,29 +614,38 @@ impl<T: Write> DisplayMode for FilesOnlyMode<T> {
 /// One file per line, followed by match count (not matched line count).
 pub struct CountMode<T: Write> {
     colors: Colors,
+    links: Option<Hyperlinks>,
+    null: bool,
     out: T,
 }
 
 impl<T: Write> CountMode<T> {
-    pub fn new(out: T, colors: Colors) -> CountMode<T> {
+    pub fn new(out: T, colors: Colors, links: Option<Hyperlinks>, null: bool) -> CountMode<T> {
         CountMode {
             colors: colors,
+            links: links,
+            null: null,
             out: out,
         }
     }
 }
 
 impl<T: Write> DisplayMode for CountMode<T> {
-    fn print_result(&mut self, res: FileResult) {
+    fn print_result(&mut self, res: FileResult) -> io::Result<()> {
         if res.matches.is_empty() {
-            return;
+            return Ok(());
         }
-        let count: usize = res.matches.iter().map(|m| m.spans.iter().count())
+        let count: usize = res.matches.iter().map(|m| m.count())
                                              .fold(0, |a, v| a + v);
+        try!(w_fname(&mut self.out, &self.colors, &mut self.links, &res.fname, b":",
+                     self.null));
         w!(self.out,
-           &self.colors.path, &res.fname.as_bytes(), &self.colors.reset,
-           &self.colors.punct, b":", &self.colors.reset,
            &self.colors.lineno, &format!("{}", count).as_bytes(), &self.colors.reset,
            b"\n");
+        Ok(())
+    }
+
+    fn print_stats(&mut self, stats: &Stats) -> io::Result<()> {
+        stats.write_to(&mut io::stderr())
     }
 }
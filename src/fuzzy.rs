@@ -0,0 +1,334 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use matcher::Matcher;
+
+/// Maximum length of a pattern: the states are kept in the bits of a u64.
+const MAX_LEN: usize = 64;
+
+/// A matcher for substrings within a given Levenshtein distance of a literal
+/// pattern.
+///
+/// This uses the bit-parallel algorithm by Wu and Manber (as in agrep).  The
+/// distance counts inserted, deleted and substituted bytes, so a non-ASCII
+/// character counts as more than one.  Matches don't span lines.
+pub struct Fuzzy {
+    /// Bit i is set in `masks[b]` if byte b matches the pattern's byte i
+    masks: Vec<u64>,
+    /// The same for the reversed pattern, to find the start of matches
+    rev_masks: Vec<u64>,
+    len: usize,
+    max_dist: usize,
+}
+
+/// Advance the states by one byte of text, given its mask.
+///
+/// After the step, `states[d]` has bit i set if the first i+1 bytes of the
+/// pattern match text ending at that byte with at most d edits.  For an
+/// anchored match, `offset` is the number of bytes since the start of the
+/// match; with 0 for every byte, a match can start anywhere.
+fn step(states: &mut [u64], mask: u64, offset: usize) {
+    // bit for the empty pattern prefix: the text since the start can be
+    // inserted with d edits
+    let start = |d| (offset <= d) as u64;
+    let mut prev = states[0];
+    states[0] = ((states[0] << 1) | start(0)) & mask;
+    for d in 1..states.len() {
+        let old = states[d];
+        states[d] = (((old << 1) | start(d)) & mask)  // matching byte
+            | prev                                      // byte inserted in the text
+            | (prev << 1) | start(d - 1)                // byte substituted
+            | (states[d - 1] << 1);                     // byte deleted from the pattern
+        prev = old;
+    }
+}
+
+impl Fuzzy {
+    /// Create a matcher for `pattern` with at most `max_dist` edits.
+    ///
+    /// If `caseless` is true, ASCII letters match regardless of case.
+    pub fn new(pattern: &[u8], max_dist: usize, caseless: bool) -> Result<Fuzzy, String> {
+        if pattern.is_empty() {
+            return Err("fuzzy pattern is empty".into());
+        }
+        if pattern.len() > MAX_LEN {
+            return Err(format!("fuzzy pattern is longer than {} bytes", MAX_LEN));
+        }
+        // otherwise, the empty string would match everywhere
+        if max_dist >= pattern.len() {
+            return Err("edit distance must be smaller than the pattern length".into());
+        }
+        let make_masks = |bytes: &mut Iterator<Item=&u8>| {
+            let mut masks = vec![0; 256];
+            for (i, &b) in bytes.enumerate() {
+                masks[b as usize] |= 1 << i;
+                if caseless {
+                    masks[b.to_ascii_lowercase() as usize] |= 1 << i;
+                    masks[b.to_ascii_uppercase() as usize] |= 1 << i;
+                }
+            }
+            masks
+        };
+        Ok(Fuzzy {
+            masks: make_masks(&mut pattern.iter()),
+            rev_masks: make_masks(&mut pattern.iter().rev()),
+            len: pattern.len(),
+            max_dist: max_dist,
+        })
+    }
+
+    /// Set the states for the start of the text (or a line).
+    fn reset(states: &mut [u64]) {
+        // with d edits, the first d bytes of the pattern can be deleted
+        for (d, state) in states.iter_mut().enumerate() {
+            *state = (1 << d) - 1;
+        }
+    }
+
+    /// Get the lowest number of edits with which the whole pattern matches.
+    fn distance(&self, states: &[u64]) -> Option<usize> {
+        let accept = 1 << (self.len - 1);
+        // the states for more edits include those for fewer
+        if states[states.len() - 1] & accept == 0 {
+            return None;
+        }
+        states.iter().position(|&s| s & accept != 0)
+    }
+
+    /// Scan `bytes` (one byte after the other, up to the end of the line) for
+    /// the first match with `masks` and at most `max_dist` edits.
+    ///
+    /// If `anchored` is true, the match has to start at the first byte, and is
+    /// extended as long as that doesn't need more edits.  Otherwise, the scan
+    /// stops at the first byte where any match ends.
+    ///
+    /// Returns the number of bytes scanned and the number of edits.
+    fn scan<'a, I>(&self, masks: &[u64], max_dist: usize, anchored: bool, bytes: I)
+                   -> Option<(usize, usize)>
+        where I: Iterator<Item=&'a u8>
+    {
+        // max_dist is smaller than the pattern length
+        let mut buf = [0; MAX_LEN];
+        let states = &mut buf[..max_dist + 1];
+        Fuzzy::reset(states);
+        let mut found = None;
+        for (i, &b) in bytes.enumerate() {
+            if b == b'\n' {
+                if anchored {
+                    break;
+                }
+                // matches don't span lines: start over after the newline
+                Fuzzy::reset(states);
+                continue;
+            }
+            step(states, masks[b as usize], if anchored { i } else { 0 });
+            match (self.distance(states), found) {
+                (Some(dist), None) if !anchored => return Some((i + 1, dist)),
+                (Some(dist), Some((_, best))) if dist > best => break,
+                (Some(dist), _) => found = Some((i + 1, dist)),
+                (None, Some(_)) => break,
+                // no match can start before the first byte anymore
+                (None, None) if anchored && i >= max_dist && states[max_dist] == 0 => break,
+                (None, None) => {}
+            }
+        }
+        found
+    }
+}
+
+impl Matcher for Fuzzy {
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        let text = &subject[start..];
+        let (end, dist) = match self.scan(&self.masks, self.max_dist, false, text.iter()) {
+            Some(found) => found,
+            None => return None,
+        };
+        // go back from the end with the reversed pattern to find the start
+        let (len, dist) = self.scan(&self.rev_masks, dist, true, text[..end].iter().rev())
+                              .expect("fuzzy match has no start");
+        // and extend the match from there, keeping the same start
+        let begin = end - len;
+        let (len, _) = self.scan(&self.masks, dist, true, text[begin..].iter())
+                           .expect("fuzzy match has no end");
+        Some((start + begin, start + begin + len))
+    }
+
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        self.find(subject).map(|span| vec![Some(span)])
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        vec![None]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::min;
+    use super::{step, Fuzzy, MAX_LEN};
+    use matcher::Matcher;
+
+    fn find(pattern: &str, max_dist: usize, subject: &str) -> Option<(usize, usize)> {
+        Fuzzy::new(pattern.as_bytes(), max_dist, false).unwrap().find(subject.as_bytes())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Fuzzy::new(b"", 0, false).is_err());
+        assert!(Fuzzy::new(&[b'a'; MAX_LEN], 1, false).is_ok());
+        assert!(Fuzzy::new(&[b'a'; MAX_LEN + 1], 1, false).is_err());
+        assert!(Fuzzy::new(b"abc", 2, false).is_ok());
+        assert!(Fuzzy::new(b"abc", 3, false).is_err());
+    }
+
+    #[test]
+    fn steps() {
+        // pattern "ab", fed "xab": states[0] is exact, states[1] one edit
+        // (bits beyond the pattern length don't matter)
+        let (a, b) = (0b01, 0b10);
+        let mut states = [0, 0b1];
+        let mut feed = |mask| {
+            step(&mut states, mask, 0);
+            [states[0] & 0b11, states[1] & 0b11]
+        };
+        assert_eq!(feed(0), [0b00, 0b01]);
+        assert_eq!(feed(a), [0b01, 0b11]);
+        assert_eq!(feed(b), [0b10, 0b11]);
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(find("hello", 0, "say hello there"), Some((4, 9)));
+        assert_eq!(find("hello", 0, "say hallo there"), None);
+        assert_eq!(find("hello", 1, "say hello there"), Some((4, 9)));
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(find("hello", 1, "say hallo there"), Some((4, 9)));
+        assert_eq!(find("hello", 1, "say hxllx there"), None);
+        assert_eq!(find("hello", 2, "say hxllx there"), Some((4, 9)));
+    }
+
+    #[test]
+    fn insertion() {
+        assert_eq!(find("hello", 1, "say helxlo there"), Some((4, 10)));
+        assert_eq!(find("hello", 1, "say hxelxlo there"), None);
+        assert_eq!(find("hello", 2, "say hxelxlo there"), Some((4, 11)));
+    }
+
+    #[test]
+    fn deletion() {
+        assert_eq!(find("hello", 1, "say helo there"), Some((4, 8)));
+        assert_eq!(find("hello", 1, "say hlo there"), None);
+        assert_eq!(find("hello", 2, "say hlo there"), Some((4, 7)));
+        // at the start and end of the subject
+        assert_eq!(find("hello", 1, "ello"), Some((0, 4)));
+        assert_eq!(find("hello", 1, "hell"), Some((0, 4)));
+    }
+
+    #[test]
+    fn caseless() {
+        let fz = Fuzzy::new(b"Hello", 1, true).unwrap();
+        assert_eq!(fz.find(b"say hELLo"), Some((4, 9)));
+        assert_eq!(fz.find(b"say HELO"), Some((4, 8)));
+        assert_eq!(find("Hello", 0, "say hELLo"), None);
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(find("hello", 1, "hel\nlo"), None);
+        assert_eq!(find("hello", 1, "say\nhelo\n"), Some((4, 8)));
+        // the match ends at the newline
+        assert_eq!(find("hello", 1, "hell\no"), Some((0, 4)));
+        // the states are reset after a newline
+        assert_eq!(find("hello", 1, "hel\nllo"), None);
+    }
+
+    #[test]
+    fn leftmost() {
+        // both "xbcd" and "bcd" are one edit away, and "abcd" none
+        assert_eq!(find("abcd", 1, "xbcdabcd"), Some((0, 4)));
+        assert_eq!(find("abcd", 1, "xxbcdabcd"), Some((1, 5)));
+    }
+
+    #[test]
+    fn find_at() {
+        let fz = Fuzzy::new(b"hello", 1, false).unwrap();
+        assert_eq!(fz.find_at(b"helo helo", 1), Some((5, 9)));
+        assert_eq!(fz.find_at(b"helo helo", 5), Some((5, 9)));
+        // "elo" is two edits away
+        assert_eq!(fz.find_at(b"helo helo", 6), None);
+        assert_eq!(fz.find_at(b"helo hello xhelo", 4), Some((5, 10)));
+        assert_eq!(fz.find_at(b"helo hello xhelo", 10), Some((12, 16)));
+    }
+
+    #[test]
+    fn single_alignment() {
+        // overlapping matches are not merged
+        assert_eq!(find("a", 0, "aaaa"), Some((0, 1)));
+        assert_eq!(find("aa", 0, "aaa"), Some((0, 2)));
+        assert_eq!(find("ca", 1, "bac"), Some((0, 2)));
+        assert_eq!(find("cc", 1, "cbcabb"), Some((0, 3)));
+    }
+
+    #[test]
+    fn anchored_steps() {
+        // pattern "ab" anchored at "xab": the x has to be inserted
+        let (a, b) = (0b01, 0b10);
+        let mut states = [0, 0b1];
+        step(&mut states, 0, 0);
+        assert_eq!([states[0] & 0b11, states[1] & 0b11], [0b00, 0b01]);
+        step(&mut states, a, 1);
+        assert_eq!([states[0] & 0b11, states[1] & 0b11], [0b00, 0b01]);
+        step(&mut states, b, 2);
+        assert_eq!([states[0] & 0b11, states[1] & 0b11], [0b00, 0b10]);
+    }
+
+    /// Levenshtein distance, computed the slow way.
+    fn distance(a: &[u8], b: &[u8]) -> usize {
+        let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+        for i in 0..a.len() {
+            let mut diag = row[0];
+            row[0] = i + 1;
+            for j in 0..b.len() {
+                let subst = diag + (a[i] != b[j]) as usize;
+                diag = row[j + 1];
+                row[j + 1] = min(subst, min(row[j], row[j + 1]) + 1);
+            }
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn random() {
+        // a simple LCG is enough to get many small cases
+        let mut seed = 12345u32;
+        let mut rand = |n| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % n
+        };
+        for _ in 0..50000 {
+            let pattern = (0..rand(5) + 1).map(|_| b"abc"[rand(3)]).collect::<Vec<_>>();
+            let text = (0..rand(12)).map(|_| b"abc\n"[rand(4)]).collect::<Vec<_>>();
+            let max_dist = rand(pattern.len());
+            let start = rand(text.len() + 1);
+            let fz = Fuzzy::new(&pattern, max_dist, false).unwrap();
+            let matches = |s: usize, e: usize| {
+                !text[s..e].contains(&b'\n') && distance(&text[s..e], &pattern) <= max_dist
+            };
+            let ends_before = |pos| (start..pos + 1).any(|e| (start..e).any(|s| matches(s, e)));
+            let case = (&pattern, max_dist, &text, start);
+            match fz.find_at(&text, start) {
+                Some((s, e)) => {
+                    assert!(start <= s && s < e && matches(s, e), "{:?}", case);
+                    // no earlier match is skipped
+                    assert!(!ends_before(s), "{:?}", case);
+                }
+                None => assert!(!ends_before(text.len()), "{:?}", case),
+            }
+        }
+    }
+}
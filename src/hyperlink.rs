@@ -0,0 +1,97 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+#[cfg(unix)]
+use libc;
+
+/// Get the host name for file:// URLs.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    String::new()
+}
+
+/// Percent-encode a path for use in a URL.
+fn encode_path(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    for &b in path.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' |
+            b'-' | b'.' | b'_' | b'~' | b'/' => result.push(b as char),
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Creates OSC 8 hyperlinks for file names and line numbers.
+#[derive(Clone)]
+pub struct Hyperlinks {
+    template: String,
+    host: String,
+    /// The last file name, and its encoded absolute path
+    last: Option<(String, String)>,
+}
+
+impl Hyperlinks {
+    /// Create from a preset name ("file" or "vscode") or a custom URL template
+    /// with the placeholders {path}, {line}, {column} and {host}.  "none"
+    /// disables links.
+    pub fn new(format: &str) -> Result<Option<Hyperlinks>, String> {
+        let template = match format {
+            "none" => return Ok(None),
+            "file" => "file://{host}{path}",
+            "vscode" => "vscode://file{path}:{line}:{column}",
+            template if template.contains("{path}") => template,
+            _ => return Err(format!("invalid hyperlink format: {} (use file, vscode, none \
+                                     or a template containing {{path}})", format)),
+        };
+        Ok(Some(Hyperlinks {
+            template: template.into(),
+            host: if template.contains("{host}") { hostname() } else { String::new() },
+            last: None,
+        }))
+    }
+
+    fn url(&mut self, fname: &str, line: Option<usize>, column: Option<usize>) -> String {
+        let is_last = self.last.as_ref().map_or(false, |&(ref name, _)| name == fname);
+        if !is_last {
+            let path = fs::canonicalize(fname)
+                .or_else(|_| env::current_dir().map(|dir| dir.join(fname)))
+                .unwrap_or_else(|_| fname.into());
+            self.last = Some((fname.into(), encode_path(&path.to_string_lossy())));
+        }
+        let path = &self.last.as_ref().unwrap().1;
+        // editors want a line and column, so use the start of the file
+        self.template.replace("{path}", path)
+                     .replace("{host}", &self.host)
+                     .replace("{line}", &format!("{}", line.unwrap_or(1)))
+                     .replace("{column}", &format!("{}", column.unwrap_or(1)))
+    }
+
+    /// Start a link to the file, or a line in it.
+    pub fn open<T: Write>(&mut self, out: &mut T, fname: &str, line: Option<usize>,
+                          column: Option<usize>) -> io::Result<()> {
+        let url = self.url(fname, line, column);
+        write!(out, "\x1b]8;;{}\x1b\\", url)
+    }
+
+    /// End the current link.
+    pub fn close<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        out.write_all(b"\x1b]8;;\x1b\\")
+    }
+}
@@ -9,6 +9,7 @@ use std::str::FromStr;
 use std::fs::{File, metadata};
 use std::io::{BufReader, BufRead};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use glob::{Pattern, MatchOptions};
 
 
@@ -81,7 +82,7 @@ fn read_git_patterns_from(path: &Path, ignores: &mut Ignores) {
 }
 
 /// Read patterns from all recognized and existing ignore files in `dir`.
-pub fn read_patterns(dir: &Path) -> Ignores {
+fn read_patterns(dir: &Path) -> Ignores {
     let mut result = Ignores {
         root: dir.to_path_buf(),
         filenames: BTreeSet::new(),
@@ -97,6 +98,24 @@ pub fn read_patterns(dir: &Path) -> Ignores {
     result
 }
 
+/// The ignore patterns for a directory, linked to those of its parent directory
+/// (which also apply to all of its contents).
+#[derive(Debug)]
+pub struct IgnoreChain {
+    ignores: Ignores,
+    parent: Option<Arc<IgnoreChain>>,
+}
+
+impl IgnoreChain {
+    /// Read the patterns for `dir`, whose parent has the chain `parent`.
+    pub fn new(dir: &Path, parent: Option<Arc<IgnoreChain>>) -> Arc<IgnoreChain> {
+        Arc::new(IgnoreChain {
+            ignores: read_patterns(dir),
+            parent: parent,
+        })
+    }
+}
+
 /// Return relative path from `base` to `path`.
 ///
 /// Copied from std::path::Path, where it is still unstable.
@@ -122,8 +141,9 @@ pub fn relative_path_from<'a, P: AsRef<Path>>(path: &'a Path, base: &'a P) -> Op
     iter_after(path.components(), base.as_ref().components()).map(|c| c.as_path())
 }
 
-/// Match `path` against the ignore stack `ignores`, return true if match found.
-pub fn match_patterns(path: &Path, ignores: &[Ignores]) -> bool {
+/// Match `path` against the ignore chain of its parent directory, return true
+/// if match found.
+pub fn match_patterns(path: &Path, chain: &IgnoreChain) -> bool {
     const OPTS: MatchOptions = MatchOptions {
         case_sensitive: true,
         require_literal_separator: true,
@@ -133,6 +153,15 @@ pub fn match_patterns(path: &Path, ignores: &[Ignores]) -> bool {
     let name = path.file_name().and_then(|s| s.to_str());
     let ext = path.extension().and_then(|s| s.to_str());
 
+    // patterns of the outermost directory are checked first
+    let mut ignores = Vec::new();
+    let mut link = Some(chain);
+    while let Some(item) = link {
+        ignores.push(&item.ignores);
+        link = item.parent.as_ref().map(|p| &**p);
+    }
+    ignores.reverse();
+
     let mut is_ignored = false;
     for ignore in ignores {
         if name.is_some() && ignore.filenames.contains(name.unwrap()) {
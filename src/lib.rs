@@ -0,0 +1,56 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+//! Ruthenium, an ack-like searcher, as a library.
+//!
+//! The `ru` binary is a front end for this library: it creates the `Opts` from
+//! the command line, chooses one of the display modes from `display`, and runs
+//! a `Searcher`.  Other programs can do the same with their own `DisplayMode`
+//! implementation as the sink for results, or use the `Walker` to go through
+//! the files `ru` would search.
+//!
+//! The result types `FileResult` and `Match` are plain data: their public
+//! fields don't change within a minor version, not even by adding new ones.
+//! Everything else (in particular the `Opts` fields and the display
+//! modes) may still change between versions.
+
+#[macro_use]
+extern crate clap;
+extern crate libc;
+extern crate atty;
+extern crate memmap;
+extern crate scoped_pool;
+extern crate num_cpus;
+extern crate glob;
+extern crate regex;
+extern crate regex_syntax;
+extern crate memchr;
+extern crate aho_corasick;
+extern crate encoding;
+
+pub mod search;
+pub mod display;
+pub mod options;
+pub mod colors;
+pub mod hyperlink;
+pub mod walk;
+pub mod stats;
+pub mod searcher;
+pub mod matcher;
+pub mod query;
+mod ignore;
+mod decode;
+mod literals;
+mod fuzzy;
+#[cfg(feature = "pcre")]
+mod pcre;
+
+pub use display::DisplayMode;
+pub use matcher::Matcher;
+pub use options::{Binaries, Casing, Engine, Opts, Sort};
+pub use search::{FileResult, Match};
+pub use searcher::Searcher;
+pub use stats::Stats;
+pub use walk::{Walker, WalkStats};
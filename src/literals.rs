@@ -0,0 +1,243 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::cmp::max;
+use aho_corasick::{AcAutomaton, Automaton, FullAcAutomaton};
+use memchr::{memchr, memrchr};
+use regex_syntax::{Expr, ExprBuilder, Repeater};
+
+use matcher::{Flags, Matcher};
+
+/// Maximum number of literals to search for at once.
+const MAX_LITERALS: usize = 64;
+
+/// Minimum length of the literals; shorter ones would give too many candidates.
+const MIN_LITERAL_LEN: usize = 2;
+
+/// Get the byte strings that a case-insensitive ASCII letter can match.
+///
+/// With Unicode, "k" and "s" also match the Kelvin sign and the long s.
+fn case_variants(b: u8) -> Vec<Vec<u8>> {
+    match b {
+        b'k' | b'K' => vec![b"k".to_vec(), b"K".to_vec(), "\u{212A}".as_bytes().to_vec()],
+        b's' | b'S' => vec![b"s".to_vec(), b"S".to_vec(), "\u{17F}".as_bytes().to_vec()],
+        b'a'...b'z' | b'A'...b'Z' => vec![vec![b.to_ascii_lowercase()],
+                                          vec![b.to_ascii_uppercase()]],
+        _ => vec![vec![b]],
+    }
+}
+
+/// Get all case variants of a literal, or None if there are too many.
+///
+/// Any part of a literal is also required, so the variants are made for the
+/// longest prefix that gives few enough of them.
+fn literal_variants(bytes: &[u8], casei: bool) -> Option<Vec<Vec<u8>>> {
+    if !casei {
+        return Some(vec![bytes.to_vec()]);
+    }
+    let mut variants = vec![vec![]];
+    for &b in bytes {
+        // non-ASCII characters can have other case variants
+        if b >= 0x80 {
+            break;
+        }
+        let next = case_variants(b);
+        if variants.len() * next.len() > MAX_LITERALS {
+            break;
+        }
+        variants = variants.iter().flat_map(|v| next.iter().map(move |n| {
+            let mut v = v.clone();
+            v.extend_from_slice(n);
+            v
+        })).collect();
+    }
+    if variants[0].is_empty() { None } else { Some(variants) }
+}
+
+/// Shortest length of a set of literals.
+fn min_len(lits: &[Vec<u8>]) -> usize {
+    lits.iter().map(|lit| lit.len()).min().unwrap_or(0)
+}
+
+/// Find a set of literals of which at least one occurs in every match of the
+/// expression, or None if there is no such (small) set.
+fn required_literals(expr: &Expr) -> Option<Vec<Vec<u8>>> {
+    match *expr {
+        Expr::Literal { ref chars, casei } => {
+            let s = chars.iter().cloned().collect::<String>();
+            literal_variants(s.as_bytes(), casei)
+        }
+        Expr::LiteralBytes { ref bytes, casei } => literal_variants(bytes, casei),
+        Expr::Group { ref e, .. } => required_literals(e),
+        Expr::Repeat { ref e, r, .. } => match r {
+            Repeater::OneOrMore => required_literals(e),
+            Repeater::Range { min, .. } if min > 0 => required_literals(e),
+            _ => None,
+        },
+        Expr::Concat(ref es) => {
+            // take the set with the longest literals, and the fewest of them
+            es.iter().filter_map(required_literals).max_by_key(|lits| {
+                (min_len(lits), !lits.len())
+            })
+        }
+        Expr::Alternate(ref es) => {
+            let mut all = Vec::new();
+            for e in es {
+                match required_literals(e) {
+                    Some(lits) => all.extend(lits),
+                    None => return None,
+                }
+            }
+            if all.len() > MAX_LITERALS { None } else { Some(all) }
+        }
+        _ => None,
+    }
+}
+
+/// Check that the expression only matches within a line (without the newline)
+/// and doesn't look at the end of the text.
+fn is_line_local(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Literal { ref chars, .. } => !chars.contains(&'\n'),
+        Expr::LiteralBytes { ref bytes, .. } => !bytes.contains(&b'\n'),
+        Expr::AnyChar | Expr::AnyByte | Expr::EndText => false,
+        Expr::Class(ref cls) => !cls.iter().any(|r| r.start <= '\n' && '\n' <= r.end),
+        Expr::ClassBytes(ref cls) => !cls.iter().any(|r| r.start <= b'\n' && b'\n' <= r.end),
+        Expr::Group { ref e, .. } | Expr::Repeat { ref e, .. } => is_line_local(e),
+        Expr::Concat(ref es) | Expr::Alternate(ref es) => es.iter().all(is_line_local),
+        _ => true,
+    }
+}
+
+/// Check if every match of the expression starts with a literal.  The regex
+/// engine already scans for such prefixes itself.
+fn has_literal_prefix(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Literal { .. } | Expr::LiteralBytes { .. } => true,
+        Expr::Group { ref e, .. } => has_literal_prefix(e),
+        Expr::Repeat { ref e, r, .. } => match r {
+            Repeater::OneOrMore => has_literal_prefix(e),
+            Repeater::Range { min, .. } if min > 0 => has_literal_prefix(e),
+            _ => false,
+        },
+        Expr::Concat(ref es) => {
+            // skip assertions, which don't consume text
+            es.iter().find(|e| match **e {
+                Expr::StartLine | Expr::StartText | Expr::WordBoundary |
+                Expr::NotWordBoundary | Expr::WordBoundaryAscii |
+                Expr::NotWordBoundaryAscii => false,
+                _ => true,
+            }).map_or(false, has_literal_prefix)
+        }
+        Expr::Alternate(ref es) => es.iter().all(has_literal_prefix),
+        _ => false,
+    }
+}
+
+/// Get the literal if the whole expression is a case-sensitive literal.
+fn pure_literal(expr: &Expr) -> Option<Vec<u8>> {
+    match *expr {
+        Expr::Literal { ref chars, casei: false } => {
+            Some(chars.iter().cloned().collect::<String>().into_bytes())
+        }
+        Expr::LiteralBytes { ref bytes, casei: false } => Some(bytes.clone()),
+        Expr::Group { ref e, .. } => pure_literal(e),
+        _ => None,
+    }
+}
+
+/// A matcher that first looks for literals that every match must contain,
+/// and only runs the regex on the lines where they are found.
+///
+/// This is only used for patterns that can't match across lines, so the
+/// results are the same as those of the regex alone.  If the pattern is just
+/// a literal, the regex isn't needed at all for finding matches.
+pub struct Prefiltered {
+    inner: Box<Matcher>,
+    literals: FullAcAutomaton<Vec<u8>>,
+    /// Whether the found literal is the match
+    exact: bool,
+}
+
+impl Prefiltered {
+    /// Wrap the matcher if the pattern has suitable required literals.
+    ///
+    /// The pattern is parsed with the Rust regex syntax; patterns that only
+    /// PCRE understands are not prefiltered.
+    pub fn wrap(inner: Box<Matcher>, pattern: &str, flags: Flags) -> Box<Matcher> {
+        // PCRE's Unicode case folding is not covered by our case variants
+        if flags.unicode && flags.caseless {
+            return inner;
+        }
+        let expr = ExprBuilder::new().allow_bytes(true)
+                                     .unicode(!pattern.is_ascii())
+                                     .case_insensitive(flags.caseless)
+                                     .multi_line(flags.multiline)
+                                     .parse(pattern);
+        let expr = match expr {
+            Ok(expr) => expr,
+            Err(_) => return inner,
+        };
+        if let Some(lit) = pure_literal(&expr) {
+            return Box::new(Prefiltered {
+                inner: inner,
+                literals: AcAutomaton::new(vec![lit]).into_full(),
+                exact: true,
+            });
+        }
+        if !is_line_local(&expr) || has_literal_prefix(&expr) {
+            return inner;
+        }
+        match required_literals(&expr) {
+            Some(lits) => {
+                if min_len(&lits) < MIN_LITERAL_LEN {
+                    return inner;
+                }
+                Box::new(Prefiltered {
+                    inner: inner,
+                    literals: AcAutomaton::new(lits).into_full(),
+                    exact: false,
+                })
+            }
+            None => inner,
+        }
+    }
+}
+
+impl Matcher for Prefiltered {
+    fn find_at(&self, subject: &[u8], mut start: usize) -> Option<(usize, usize)> {
+        while start < subject.len() {
+            let pos = match self.literals.find(&subject[start..]).next() {
+                Some(found) if self.exact => return Some((start + found.start,
+                                                          start + found.end)),
+                Some(found) => start + found.start,
+                None => return None,
+            };
+            // the regex sees the whole text before the candidate line, so that
+            // e.g. lookbehind works as usual, but no text after it
+            let line_start = memrchr(b'\n', &subject[..pos]).map_or(0, |i| i + 1);
+            let line_end = memchr(b'\n', &subject[pos..]).map_or(subject.len(),
+                                                                 |i| pos + i + 1);
+            let m = self.inner.find_at(&subject[..line_end], max(start, line_start));
+            if m.is_some() {
+                return m;
+            }
+            start = line_end;
+        }
+        None
+    }
+
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        self.inner.captures(subject)
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        self.inner.capture_names()
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        self.inner.capture_index(name)
+    }
+}
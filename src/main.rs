@@ -3,148 +3,112 @@
 // Licensed under the MIT license.
 // ---------------------------------------------------------------------------------------
 
-#[macro_use]
-extern crate clap;
+extern crate ruthenium;
 extern crate libc;
-extern crate atty;
-extern crate walkdir;
-extern crate memmap;
-extern crate scoped_pool;
-extern crate num_cpus;
-extern crate glob;
-extern crate regex;
 
-mod search;
-mod ignore;
-mod display;
-mod options;
-#[cfg(feature = "pcre")]
-mod pcre;
+mod pager;
 
-use std::cmp::max;
-use std::sync::mpsc::{sync_channel, SyncSender};
-use std::thread;
-use std::io::{stdout, BufWriter};
-use memmap::{Mmap, Protection};
-use scoped_pool::Pool;
-use walkdir::WalkDirIterator;
+use std::io::{self, stderr, stdout, BufWriter, Write};
+use std::process;
+use std::sync::Arc;
 
-use display::DisplayMode;
-use search::FileResult;
-use options::Opts;
+use ruthenium::display::{self, DisplayMode, LineLimit};
+use ruthenium::query::Query;
+use ruthenium::{search, Matcher, Opts, Searcher};
 
+/// The matcher and query returned by `create_rx`.
+type Compiled = (Arc<Matcher>, Option<Arc<Query>>);
 
-/// Walk a directory (given in Opts) and check all found files.
-///
-/// The channel is used to send result structs to the main thread, which gives
-/// them to the DisplayMode for output.
-///
-/// The thread of this function only does the directory walking, it spawns a
-/// number of worker threads in a pool to grep individual files.
-fn walk(chan: SyncSender<FileResult>, opts: &Opts) {
-    // thread pool for individual file grep worker threads
-    let pool = Pool::new(max(opts.workers - 1, 1));
-    // create the regex object
-    let regex = search::create_rx(&opts);
+/// Run the search.  This is separated from `run_with` so that it can get a
+/// generic DisplayMode argument.
+fn run<D: DisplayMode>(display: &mut D, opts: Opts, compiled: Compiled) -> io::Result<()> {
+    let (matcher, query) = compiled;
+    Searcher::from_opts(opts).compiled(matcher, query).run(display).map(|_| ())
+}
 
-    let walker = walkdir::WalkDir::new(&opts.path)
-        .follow_links(opts.follow_links)
-        .max_depth(opts.depth);
-    pool.scoped(|scope| {
-        let rx = &regex;  // borrow for closures
-        // stack of directories being walked, maintained in the filter closure
-        let mut parent_stack: Vec<::std::path::PathBuf> = Vec::new();
-        // stack of Ignore structs per directory in parent_stack, they accumulate
-        // XXX: add global ignores from cmdline and a config file here
-        let mut ignore_stack = Vec::new();
-        let walker = walker.into_iter().filter_entry(|entry| {
-            // remove parents from stack that are not applicable anymore
-            let new_parent = entry.path().parent().unwrap();
-            while !parent_stack.is_empty() &&
-                parent_stack.last().unwrap().as_path() != new_parent
-            {
-                ignore_stack.pop();
-                parent_stack.pop();
-            }
-            // weed out hidden files (this is separate from ignored)
-            let path = entry.path();
-            if let Some(fname) = path.file_name() {
-                if !opts.do_hidden && fname.to_string_lossy().starts_with(".") {
-                    return false;
-                }
-            }
-            // weed out ignored files and directories (if we return false here for
-            // directories, the contents are pruned from the iterator)
-            if opts.check_ignores && ignore::match_patterns(path, &ignore_stack) {
-                return false;
-            }
-            // we got a new dir? put it onto the stack
-            if entry.file_type().is_dir() {
-                let new_path = entry.path().to_path_buf();
-                // read ignore patterns specific to this directory
-                ignore_stack.push(ignore::read_patterns(&new_path));
-                parent_stack.push(new_path);
-            }
-            true
-        });
-        for entry in walker {
-            if let Ok(entry) = entry {
-                // only touch normal files
-                if !entry.file_type().is_file() {
-                    continue;
-                }
-                // open and search file in one of the worker threads
-                let ch = chan.clone();
-                scope.execute(move || {
-                    let path = entry.path();
-                    if let Ok(map) = Mmap::open_path(path, Protection::Read) {
-                        let buf = unsafe { map.as_slice() };
-                        let res = search::search(rx, &opts, path, buf);
-                        ch.send(res).unwrap();
-                    }
-                });
-            }
+/// Select the display mode and run the search, printing to `writer`.
+fn run_with<W: Write>(writer: W, mut opts: Opts, compiled: Compiled) -> io::Result<()> {
+    let colors = opts.colors.take().unwrap();  // guaranteed to be Some()
+    let links = opts.hyperlinks.take();
+    let limit = LineLimit::new(opts.max_columns, opts.max_columns_preview);
+    let mut writer = BufWriter::new(writer);
+
+    // determine which display mode we are using
+    try!({
+        let writer = &mut writer;
+        if opts.only_count {
+            run(&mut display::CountMode::new(writer, colors, links, opts.null_fnames),
+                opts, compiled)
+        } else if opts.only_files == Some(true) {
+            run(&mut display::FilesOnlyMode::new(writer, colors, links, true,
+                                                 opts.null_fnames), opts, compiled)
+        } else if opts.only_files == Some(false) {
+            run(&mut display::FilesOnlyMode::new(writer, colors, links, false,
+                                                 opts.null_fnames), opts, compiled)
+        } else if opts.ackmate_format {
+            run(&mut display::AckMateMode::new(writer, limit, opts.null_fnames), opts,
+                compiled)
+        } else if opts.vimgrep_format {
+            run(&mut display::VimGrepMode::new(writer, limit, opts.null_fnames), opts, compiled)
+        } else if opts.hexdump {
+            run(&mut display::HexDumpMode::new(writer, colors, links, limit, opts.show_break,
+                                               opts.show_heading, opts.null_fnames),
+                opts, compiled)
+        } else {
+            run(&mut display::DefaultMode::new(writer, colors, links, limit, opts.show_break,
+                                               opts.show_heading, opts.null_fnames),
+                opts, compiled)
         }
     });
+    writer.flush()
 }
 
-/// Run the main action.  This is separated from `main` so that it can get a generic
-/// DisplayMode argument.
-///
-/// Spawns the walker thread and prints the results.
-fn run<D: DisplayMode>(display: &mut D, opts: Opts) {
-    // The sync_channel has a bound on pending items.  We don't want to
-    // generate results much faster than we can print them.
-    let (w_chan, r_chan) = sync_channel(4 * opts.workers as usize);
-    thread::spawn(move || {
-        walk(w_chan, &opts);
-    });
-    while let Ok(r) = r_chan.recv() {
-        display.print_result(r);
-    }
-}
+/// Exit status after the output was closed early, as if killed by SIGPIPE.
+#[cfg(unix)]
+const BROKEN_PIPE_STATUS: i32 = 128 + libc::SIGPIPE;
+#[cfg(not(unix))]
+const BROKEN_PIPE_STATUS: i32 = 1;
 
 /// Main entry point.
 fn main() {
     let mut opts = Opts::from_cmdline();
-    let colors = opts.colors.take().unwrap();  // guaranteed to be Some()
+    let compiled = match search::create_rx(&opts) {
+        Ok((matcher, query, note)) => {
+            if let Some(note) = note {
+                let _ = writeln!(stderr(), "ru: {}", note);
+            }
+            (matcher, query)
+        }
+        Err(msg) => {
+            let _ = writeln!(stderr(), "ru: invalid pattern: {}", msg);
+            process::exit(2);
+        }
+    };
 
-    let stdout = stdout();
-    let writer = BufWriter::new(stdout.lock());
+    if let Some(cmd) = opts.pager.take() {
+        match pager::spawn(&cmd) {
+            Ok((mut child, stdin)) => {
+                // quitting the pager early is no error
+                let _ = run_with(stdin, opts, compiled);
+                // the pager's input is closed now, let it show everything
+                let _ = child.wait();
+                return;
+            }
+            Err(e) => {
+                let _ = writeln!(stderr(), "ru: cannot start pager {:?}: {}", cmd, e);
+            }
+        }
+    }
 
-    // determine which display mode we are using
-    if opts.only_count {
-        run(&mut display::CountMode::new(writer, colors), opts);
-    } else if opts.only_files == Some(true) {
-        run(&mut display::FilesOnlyMode::new(writer, colors, true), opts);
-    } else if opts.only_files == Some(false) {
-        run(&mut display::FilesOnlyMode::new(writer, colors, false), opts);
-    } else if opts.ackmate_format {
-        run(&mut display::AckMateMode::new(writer), opts);
-    } else if opts.vimgrep_format {
-        run(&mut display::VimGrepMode::new(writer), opts);
-    } else {
-        run(&mut display::DefaultMode::new(writer, colors, opts.show_break,
-                                           opts.show_heading), opts);
+    let stdout = stdout();
+    match run_with(stdout.lock(), opts, compiled) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            process::exit(BROKEN_PIPE_STATUS);
+        }
+        Err(e) => {
+            let _ = writeln!(stderr(), "ru: error writing output: {}", e);
+            process::exit(2);
+        }
     }
 }
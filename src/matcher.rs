@@ -0,0 +1,291 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::sync::Arc;
+use regex::bytes::Regex;
+
+use fuzzy::Fuzzy;
+
+#[cfg(feature = "pcre")]
+use pcre;
+
+/// Options for compiling a pattern, for all engines unless noted.
+#[derive(Clone, Copy, Default)]
+pub struct Flags {
+    /// Match letters case-insensitively
+    pub caseless: bool,
+    /// Let ^ and $ match at line boundaries
+    pub multiline: bool,
+    /// PCRE only: UTF-8 and Unicode properties (the Rust engine always
+    /// matches non-ASCII pattern characters as UTF-8)
+    pub unicode: bool,
+    /// PCRE only: maximum size of the JIT stack in bytes
+    pub jit_stack: Option<usize>,
+}
+
+/// A compiled pattern that can find matches in byte buffers.
+///
+/// This is implemented by all regex engines, so that the engine can be
+/// selected at runtime.
+pub trait Matcher: Send + Sync {
+    /// Find the first match at or after `start`, as a (start, end) span.
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)>;
+
+    /// Find the first match in the subject.
+    fn find(&self, subject: &[u8]) -> Option<(usize, usize)> {
+        self.find_at(subject, 0)
+    }
+
+    /// Check if the pattern matches anywhere in the subject.
+    fn is_match(&self, subject: &[u8]) -> bool {
+        self.find(subject).is_some()
+    }
+
+    /// Find the first match and return the spans of all capture groups (the
+    /// whole match is group 0, groups that didn't participate are None).
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>>;
+
+    /// Get the names of all capture groups, by index (None for unnamed
+    /// groups and group 0).
+    fn capture_names(&self) -> Vec<Option<String>>;
+
+    /// Get the index of a named capture group.
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        self.capture_names().iter().position(|n| n.as_ref().map_or(false, |n| n == name))
+    }
+}
+
+impl Matcher for Regex {
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        Regex::find_at(self, subject, start)
+    }
+
+    fn is_match(&self, subject: &[u8]) -> bool {
+        Regex::is_match(self, subject)
+    }
+
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        Regex::captures(self, subject).map(|caps| (0..caps.len()).map(|i| caps.pos(i)).collect())
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        Regex::capture_names(self).map(|n| n.map(String::from)).collect()
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        Regex::capture_names(self).position(|n| n == Some(name))
+    }
+}
+
+/// A shared matcher, e.g. to use it in a `Query` as well as on its own.
+impl Matcher for Arc<Matcher> {
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        (**self).find_at(subject, start)
+    }
+
+    fn is_match(&self, subject: &[u8]) -> bool {
+        (**self).is_match(subject)
+    }
+
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        (**self).captures(subject)
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        (**self).capture_names()
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        (**self).capture_index(name)
+    }
+}
+
+#[cfg(feature = "pcre")]
+impl Matcher for pcre::Regex {
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        pcre::Regex::find_at(self, subject, start)
+    }
+
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        pcre::Regex::captures(self, subject)
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        pcre::Regex::capture_names(self).to_vec()
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        pcre::Regex::capture_index(self, name)
+    }
+}
+
+/// Expand a replacement template with the groups of a match (as returned by
+/// `Matcher::captures`) in `subject`.
+///
+/// `$1` and `${1}` refer to groups by index, `$name` and `${name}` by name,
+/// and `$$` is a literal `$`.  As with the Rust regex engine, `$name` takes
+/// the longest possible name, and groups that don't exist or didn't
+/// participate in the match are replaced by nothing.
+pub fn expand(matcher: &Matcher, subject: &[u8], spans: &[Option<(usize, usize)>],
+              template: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.iter().position(|&b| b == b'$') {
+        result.extend_from_slice(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if rest.first() == Some(&b'$') {
+            result.push(b'$');
+            rest = &rest[1..];
+            continue;
+        }
+        let (name, len) = if rest.first() == Some(&b'{') {
+            match rest.iter().position(|&b| b == b'}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => (&rest[..0], 0),
+            }
+        } else {
+            let end = rest.iter().position(|&b| match b {
+                b'0'...b'9' | b'a'...b'z' | b'A'...b'Z' | b'_' => false,
+                _ => true,
+            }).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() {
+            // not a group reference
+            result.push(b'$');
+            continue;
+        }
+        rest = &rest[len..];
+        let name = String::from_utf8_lossy(name);
+        let index = name.parse::<usize>().ok().or_else(|| matcher.capture_index(&name));
+        if let Some(&Some((start, end))) = index.and_then(|i| spans.get(i)) {
+            result.extend_from_slice(&subject[start..end]);
+        }
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+/// Compile a pattern with the Rust regex engine.
+pub fn new_regex(pattern: &str, flags: Flags) -> Result<Box<Matcher>, String> {
+    let mut pattern = pattern.to_owned();
+    if flags.multiline {
+        pattern = format!("(?m){}", pattern);
+    }
+    if flags.caseless {
+        pattern = format!("(?i){}", pattern);
+    }
+    // byte regexes only match ASCII by default, but non-ASCII characters
+    // should match their UTF-8 encoding (which transcoded files are in)
+    if !pattern.is_ascii() {
+        pattern = format!("(?u){}", pattern);
+    }
+    match Regex::new(&pattern) {
+        Ok(rx) => Ok(Box::new(rx)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Compile a pattern with PCRE.
+#[cfg(feature = "pcre")]
+pub fn new_pcre(pattern: &str, flags: Flags) -> Result<Box<Matcher>, String> {
+    let mut options = 0;
+    if flags.caseless {
+        options |= pcre::CASELESS;
+    }
+    if flags.multiline {
+        options |= pcre::MULTILINE;
+    }
+    if flags.unicode {
+        options |= pcre::UNICODE;
+    }
+    match pcre::Regex::new(pattern, options, flags.jit_stack) {
+        Ok(rx) => Ok(Box::new(rx)),
+        Err(err) => Err(format!("PCRE2: {}", err)),
+    }
+}
+
+/// Compile a pattern with PCRE.
+#[cfg(not(feature = "pcre"))]
+pub fn new_pcre(_pattern: &str, _flags: Flags) -> Result<Box<Matcher>, String> {
+    Err("PCRE support is not compiled in (build with the \"pcre\" feature)".into())
+}
+
+/// Compile a literal pattern for fuzzy matching with at most `max_dist` edits.
+pub fn new_fuzzy(pattern: &str, max_dist: usize, flags: Flags) -> Result<Box<Matcher>, String> {
+    Fuzzy::new(pattern.as_bytes(), max_dist, flags.caseless).map(|m| Box::new(m) as Box<Matcher>)
+}
+
+/// Check if a pattern uses syntax that only PCRE supports: backreferences,
+/// lookaround and atomic groups.
+pub fn needs_pcre(pattern: &str) -> bool {
+    let bytes = pattern.as_bytes();
+    let mut in_class = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                match bytes.get(i + 1) {
+                    Some(&b'1'...b'9') | Some(&b'g') | Some(&b'k') if !in_class => return true,
+                    _ => i += 1,  // skip the escaped character
+                }
+            }
+            b'[' if !in_class => {
+                in_class = true;
+                // a "]" at the start of the class is literal
+                if bytes.get(i + 1) == Some(&b'^') {
+                    i += 1;
+                }
+                if bytes.get(i + 1) == Some(&b']') {
+                    i += 1;
+                }
+            }
+            b']' => in_class = false,
+            b'(' if !in_class => {
+                let group = &bytes[i + 1..];
+                if [&b"?="[..], b"?!", b"?<=", b"?<!", b"?>"].iter()
+                                                             .any(|p| group.starts_with(p)) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Check if the PCRE engine is available in this build.
+pub fn has_pcre() -> bool {
+    cfg!(feature = "pcre")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flags, Matcher, expand, new_regex};
+
+    fn check_expand(matcher: &Matcher) {
+        let subject = b"on 2016-01-31.";
+        let spans = matcher.captures(subject).unwrap();
+        let exp = |template: &str| expand(matcher, subject, &spans, template.as_bytes());
+        assert_eq!(exp("${d}.$2.${year}"), b"31.01.2016");
+        assert_eq!(exp("$year$d $yeard"), b"201631 ");
+        assert_eq!(exp("${year}d"), b"2016d");
+        assert_eq!(exp("$0 $4 ${month} $$1 $ ${"), b"2016-01-31   $1 $ ${");
+    }
+
+    #[test]
+    fn expand_regex() {
+        let rx = new_regex(r"(?P<year>\d{4})-(\d\d)-(?P<d>\d\d)", Flags::default()).unwrap();
+        check_expand(&*rx);
+    }
+
+    #[test]
+    #[cfg(feature = "pcre")]
+    fn expand_pcre() {
+        let rx = super::new_pcre(r"(?<year>\d{4})-(\d\d)-(?<d>\d\d)", Flags::default()).unwrap();
+        check_expand(&*rx);
+    }
+}
@@ -4,50 +4,22 @@
 // ---------------------------------------------------------------------------------------
 
 use std::cmp::min;
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
 use std::usize;
 
 use atty;
-use clap::{App, AppSettings, Arg};
+use colors::{ColorSpecs, Colors};
+use hyperlink::Hyperlinks;
+use matcher;
+use clap::{App, AppSettings, Arg, Error, ErrorKind};
+use encoding::EncodingRef;
+use encoding::label::encoding_from_whatwg_label;
 use num_cpus;
 
-/// Contains the ANSI codes needed to set the terminal to a certain color.
-#[derive(Clone)]
-pub struct Colors {
-    pub reset: Vec<u8>,
-    pub path: Vec<u8>,
-    pub lineno: Vec<u8>,
-    pub span: Vec<u8>,
-    pub punct: Vec<u8>,
-    pub empty: bool,
-}
-
-impl Colors {
-    /// Create a struct where no colors are emitted.
-    fn empty() -> Colors {
-        Colors {
-            reset: vec![],
-            path: vec![],
-            lineno: vec![],
-            span: vec![],
-            punct: vec![],
-            empty: true,
-        }
-    }
-
-    /// Create a struct from given color specs.  Color specs are the payload
-    /// of the color ANSI sequences, e.g. "01;31".
-    fn from(path: &str, lineno: &str, span: &str, punct: &str) -> Colors {
-        Colors {
-            reset: b"\x1b[0m".to_vec(),
-            path: format!("\x1b[{}m", path).into_bytes(),
-            lineno: format!("\x1b[{}m", lineno).into_bytes(),
-            span: format!("\x1b[{}m", span).into_bytes(),
-            punct: format!("\x1b[{}m", punct).into_bytes(),
-            empty: false,
-        }
-    }
-}
-
 /// Case-sensitivity matching options.
 ///
 /// Smart casing means insensitive as long as the pattern contains no uppercase
@@ -59,6 +31,47 @@ pub enum Casing {
     Insensitive,
 }
 
+/// Which regex engine to use.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// The Rust regex crate
+    Regex,
+    /// PCRE (only available with the "pcre" feature)
+    Pcre,
+    /// The Rust regex crate, or PCRE for patterns it doesn't support
+    Auto,
+    /// No regex: find the pattern as a literal with at most this many edits
+    Fuzzy(usize),
+}
+
+/// How to treat files that are detected as binary.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Binaries {
+    /// Only report whether the file matches
+    Report,
+    /// Search the file like a text file
+    Text,
+    /// Assume that the file doesn't match
+    Skip,
+}
+
+/// Order in which results are printed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sort {
+    /// As soon as a worker thread is done with the file
+    Unordered,
+    /// In the order the files are found while walking
+    Walk,
+    /// By path
+    Path,
+    /// By time of last modification
+    Modified,
+    /// By time of last access
+    Accessed,
+    /// By time of creation
+    Created,
+}
+
 /// Holds all options for the search.
 #[derive(Clone)]
 pub struct Opts {
@@ -66,30 +79,89 @@ pub struct Opts {
     pub path: String,
     pub depth: usize,
     pub follow_links: bool,
-    pub do_binaries: bool,
+    pub binaries: Binaries,
     pub do_hidden: bool,
+    pub encoding: Option<EncodingRef>,
     // ignore file related options
     pub check_ignores: bool,
     // pattern related options
     pub pattern: String,
+    pub and_patterns: Vec<String>,
+    pub or_patterns: Vec<String>,
+    pub not_patterns: Vec<String>,
+    pub near: Option<(usize, String)>,
     pub casing: Casing,
     pub literal: bool,
+    pub engine: Engine,
+    pub pcre2_unicode: bool,
+    pub pcre2_jit_stack: Option<usize>,
     pub invert: bool,
+    pub null_data: bool,
+    pub crlf: bool,
     // display related options
     pub colors: Option<Colors>,
+    pub hyperlinks: Option<Hyperlinks>,
+    pub pager: Option<String>,
     pub only_files: Option<bool>,
     pub only_count: bool,
     pub show_break: bool,
     pub show_heading: bool,
     pub ackmate_format: bool,
     pub vimgrep_format: bool,
+    pub hexdump: bool,
+    pub null_fnames: bool,
+    pub sort: Sort,
+    pub sort_reverse: bool,
+    pub stats: bool,
     pub max_count: usize,
+    pub max_files: usize,
+    pub max_matches: usize,
+    pub max_columns: Option<usize>,
+    pub max_columns_preview: bool,
     pub before: usize,
     pub after: usize,
     // others
     pub workers: usize,
 }
 
+/// Read the default arguments from the config file and the RU_OPTIONS
+/// environment variable.
+///
+/// The config file is $RU_CONFIG_PATH, or ~/.config/ruthenium/config.  It has
+/// one argument per line; empty lines and lines starting with "#" are ignored.
+/// RU_OPTIONS is split at whitespace.  Arguments from RU_OPTIONS come after
+/// (and therefore override) those from the file.
+fn default_args() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let (path, explicit) = match env::var_os("RU_CONFIG_PATH") {
+        Some(path) => (Some(PathBuf::from(path)), true),
+        None => (env::var_os("HOME").map(|home| {
+            PathBuf::from(home).join(".config").join("ruthenium").join("config")
+        }), false),
+    };
+    if let Some(path) = path {
+        let mut contents = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => {
+                args.extend(contents.lines()
+                            .map(|line| line.trim())
+                            .filter(|line| !line.is_empty() && !line.starts_with("#"))
+                            .map(OsString::from));
+            }
+            // a missing default config file is fine
+            Err(e) => if explicit {
+                Error::with_description(&format!("cannot read config file {}: {}",
+                                                 path.display(), e),
+                                        ErrorKind::Io).exit()
+            }
+        }
+    }
+    if let Ok(options) = env::var("RU_OPTIONS") {
+        args.extend(options.split_whitespace().map(OsString::from));
+    }
+    args
+}
+
 /// Somewhat simpler creation of flag Args.
 macro_rules! flag {
     ($n:ident -$f:ident) => {
@@ -104,6 +176,61 @@ macro_rules! flag {
 }
 
 impl Opts {
+    /// Create options for searching `pattern` in the current directory, with
+    /// the defaults used for non-terminal output.
+    pub fn new(pattern: &str) -> Opts {
+        Opts {
+            // file related
+            path: ".".into(),
+            depth: usize::MAX,
+            follow_links: false,
+            binaries: Binaries::Skip,
+            do_hidden: false,
+            encoding: None,
+            // ignore file related
+            check_ignores: true,
+            // pattern related
+            pattern: pattern.into(),
+            and_patterns: Vec::new(),
+            or_patterns: Vec::new(),
+            not_patterns: Vec::new(),
+            near: None,
+            casing: Casing::Smart,
+            literal: false,
+            engine: Engine::Auto,
+            pcre2_unicode: false,
+            pcre2_jit_stack: None,
+            invert: false,
+            null_data: false,
+            crlf: false,
+            // display related
+            colors: Some(Colors::empty()),
+            hyperlinks: None,
+            pager: None,
+            only_files: None,
+            only_count: false,
+            show_break: false,
+            show_heading: false,
+            ackmate_format: false,
+            vimgrep_format: false,
+            hexdump: false,
+            null_fnames: false,
+            sort: Sort::Walk,
+            sort_reverse: false,
+            stats: false,
+            max_count: usize::MAX,
+            max_files: usize::MAX,
+            max_matches: usize::MAX,
+            max_columns: None,
+            max_columns_preview: false,
+            before: 0,
+            after: 0,
+            // other
+            workers: min(4, num_cpus::get()),
+        }
+    }
+
+    /// Create options from the command line (and configured defaults).
     pub fn from_cmdline() -> Opts {
         let version = format!("v{}", crate_version!());
         // XXX: sort and group the arguments once they are all done
@@ -113,65 +240,126 @@ impl Opts {
             .about("Recursively search for a pattern, like ack")
             .setting(AppSettings::UnifiedHelpMessage)
             .setting(AppSettings::ArgRequiredElseHelp)  // seems to be not working
+            // so that the command line can override defaults from the config
+            .setting(AppSettings::AllArgsOverrideSelf)
             .arg(Arg::with_name("pattern").required(true).index(1))
             .arg(Arg::with_name("path").index(2))
             .arg(flag!(all -a --"all-types"))
             .arg(flag!(depth / --"depth").takes_value(true))
+            .arg(flag!(and / --"and").takes_value(true).multiple(true).number_of_values(1))
+            .arg(flag!(or / --"or").takes_value(true).multiple(true).number_of_values(1))
+            .arg(flag!(not / --"not").takes_value(true).multiple(true).number_of_values(1))
+            .arg(flag!(near / --"near").takes_value(true).multiple(true).number_of_values(2)
+                 .value_names(&["N", "PATTERN"]).conflicts_with("invert"))
             .arg(flag!(literal -Q --"literal"))
             .arg(flag!(fixedstrings -F --"fixed-strings"))
+            .arg(flag!(engine / --"engine").takes_value(true)
+                 .possible_values(&["regex", "pcre", "auto"]))
+            .arg(flag!(fuzzy / --"fuzzy").takes_value(true).min_values(0).require_equals(true)
+                 .conflicts_with("engine"))
+            .arg(flag!(pcre2unicode / --"pcre2-unicode"))
+            .arg(flag!(pcre2jitstack / --"pcre2-jit-stack").takes_value(true))
             .arg(flag!(alltext -t --"all-text").conflicts_with("all"))
             .arg(flag!(unrestricted -u --"unrestricted").conflicts_with("all"))
             .arg(flag!(searchbinary / --"search-binary"))
+            .arg(flag!(binaryfiles / --"binary-files").takes_value(true)
+                 .possible_values(&["binary", "text", "without-match"]))
             .arg(flag!(searchhidden / --"hidden"))
             .arg(flag!(fileswith -l --"files-with-matches"))
             .arg(flag!(fileswithout -L --"files-without-matches").conflicts_with("fileswith"))
             .arg(flag!(count -c --"count").conflicts_with("fileswith"))
             .arg(flag!(follow -f --"follow"))
-            .arg(flag!(nofollow / --"nofollow").conflicts_with("follow"))
+            .arg(flag!(nofollow / --"nofollow").overrides_with("follow"))
             .arg(flag!(nocolor / --"nocolor"))
-            .arg(flag!(color / --"color"))
+            .arg(flag!(color / --"color").overrides_with("nocolor"))
             .arg(flag!(colorlineno / --"color-line-number").takes_value(true))
             .arg(flag!(colorspan / --"color-match").takes_value(true))
             .arg(flag!(colorpath / --"color-path").takes_value(true))
             .arg(flag!(colorpunct / --"color-punct").takes_value(true))
-            .arg(flag!(casesens -s --"case-sensitive").conflicts_with("caseinsens"))
-            .arg(flag!(casesmart -S --"smart-case").conflicts_with("casesens"))
-            .arg(flag!(caseinsens -i --"ignore-case").conflicts_with("casesmart"))
+            .arg(flag!(colors / --"colors").takes_value(true).multiple(true)
+                 .number_of_values(1))
+            .arg(flag!(hyperlinkformat / --"hyperlink-format").takes_value(true))
+            .arg(flag!(pager / --"pager").takes_value(true).min_values(0).require_equals(true))
+            .arg(flag!(nopager / --"no-pager").overrides_with("pager"))
+            .arg(flag!(casesens -s --"case-sensitive")
+                 .overrides_with_all(&["casesmart", "caseinsens"]))
+            .arg(flag!(casesmart -S --"smart-case")
+                 .overrides_with_all(&["casesens", "caseinsens"]))
+            .arg(flag!(caseinsens -i --"ignore-case")
+                 .overrides_with_all(&["casesens", "casesmart"]))
             .arg(flag!(group / --"group"))
-            .arg(flag!(nogroup / --"nogroup").conflicts_with("gorup"))
+            .arg(flag!(nogroup / --"nogroup").overrides_with("group"))
             .arg(flag!(heading -H --"heading"))
-            .arg(flag!(noheading / --"noheading").conflicts_with("heading"))
+            .arg(flag!(noheading / --"noheading").overrides_with("heading"))
             .arg(flag!(break / --"break"))
-            .arg(flag!(nobreak / --"nobreak").conflicts_with("break"))
+            .arg(flag!(nobreak / --"nobreak").overrides_with("break"))
             .arg(flag!(ackmate / --"ackmate"))
             .arg(flag!(vimgrep / --"vimgrep"))
+            .arg(flag!(hexdump -X --"hexdump"))
+            .arg(Arg::with_name("null").short("0").long("null"))
+            .arg(flag!(sort / --"sort").takes_value(true)
+                 .possible_values(&["none", "path", "modified", "accessed", "created"]))
+            .arg(flag!(sortr / --"sortr").takes_value(true).conflicts_with("sort")
+                 .possible_values(&["path", "modified", "accessed", "created"]))
+            .arg(flag!(stats / --"stats"))
             .arg(flag!(maxcount -m --"max-count").takes_value(true))
+            .arg(flag!(maxfiles / --"max-files").takes_value(true))
+            .arg(flag!(maxmatches / --"max-matches").takes_value(true))
+            .arg(flag!(maxcolumns -M --"max-columns").takes_value(true))
+            .arg(flag!(maxcolumnspreview / --"max-columns-preview"))
             .arg(flag!(before -B --"before").takes_value(true))
             .arg(flag!(after -A --"after").takes_value(true))
             .arg(flag!(context -C --"context").takes_value(true))
             .arg(flag!(workers / --"workers").takes_value(true))
             .arg(flag!(invert -v --"invert-match"))
+            .arg(flag!(encoding -E --"encoding").takes_value(true))
+            .arg(flag!(nulldata -z --"null-data"))
+            .arg(flag!(crlf / --"crlf").conflicts_with("nulldata"))
+            .arg(flag!(noconfig / --"no-config"))
             ;
-        let m = app.get_matches();
+        // the command line is parsed on its own first, to find --no-config only
+        // where it is an option (not after "--" or as the value of another
+        // option); if that fails, the full parse below reports the error
+        let no_config = app.clone().get_matches_from_safe(env::args_os())
+                           .map(|m| m.is_present("noconfig")).unwrap_or(false);
+        let defaults = if no_config { Vec::new() } else { default_args() };
+        // default arguments go between the program name and the real ones
+        let mut args = env::args_os();
+        let m = app.get_matches_from(args.next().into_iter()
+                                     .chain(defaults)
+                                     .chain(args));
 
         // process option values
         let depth = m.value_of("depth").and_then(|v| v.parse::<usize>().ok())
                                        .map(|v| v + 1) // 0 == immediate children
                                        .unwrap_or(usize::MAX);
 
-        let mut binaries = m.is_present("searchbinary");
+        let mut binaries = if m.is_present("searchbinary") {
+            Binaries::Report
+        } else {
+            Binaries::Skip
+        };
         let mut hidden = m.is_present("searchhidden");
         let mut ignores = true;
         if m.is_present("all") {
-            binaries = true;
+            binaries = Binaries::Report;
             ignores = false;
         } else if m.is_present("alltext") {
             ignores = false;
         } else if m.is_present("unrestricted") {
-            binaries = true;
+            binaries = Binaries::Report;
             hidden = true;
             ignores = false;
         }
+        if m.is_present("hexdump") {
+            binaries = Binaries::Report;
+        }
+        match m.value_of("binaryfiles") {
+            Some("binary") => binaries = Binaries::Report,
+            Some("text") => binaries = Binaries::Text,
+            Some("without-match") => binaries = Binaries::Skip,
+            _ => {}
+        }
 
         let mut casing = Casing::Smart;
         if m.is_present("caseinsens") {
@@ -184,18 +372,70 @@ impl Opts {
             literal = true;
         }
 
+        let mut engine = match m.value_of("engine") {
+            Some("regex") => Engine::Regex,
+            Some("pcre") => Engine::Pcre,
+            _ => Engine::Auto,
+        };
+        if m.is_present("fuzzy") {
+            engine = Engine::Fuzzy(m.value_of("fuzzy").map_or(1, |v| {
+                v.parse().unwrap_or_else(|_| {
+                    Error::with_description(&format!("invalid edit distance: {}", v),
+                                            ErrorKind::InvalidValue).exit()
+                })
+            }));
+        }
+        if engine == Engine::Pcre && !matcher::has_pcre() {
+            Error::with_description("PCRE support is not compiled in (build with the \
+                                     \"pcre\" feature)", ErrorKind::InvalidValue).exit();
+        }
+
+        let encoding = m.value_of("encoding").map(|label| {
+            encoding_from_whatwg_label(label).unwrap_or_else(|| {
+                Error::with_description(&format!("unknown encoding: {}", label),
+                                        ErrorKind::InvalidValue).exit()
+            })
+        });
+
         let out_to_tty = atty::is();
         let colors = if !m.is_present("color") &&
             (!out_to_tty || m.is_present("nocolor"))
         {
             Colors::empty()
         } else {
-            Colors::from(
-                m.value_of("colorpath").unwrap_or("35"),
-                m.value_of("colorlineno").unwrap_or("32"),
-                m.value_of("colorspan").unwrap_or("4"),
-                m.value_of("colorpunct").unwrap_or("36"),
-            )
+            let mut specs = ColorSpecs::new();
+            let mut result = Ok(());
+            if let Ok(value) = env::var("GREP_COLORS") {
+                result = result.and_then(|_| specs.apply_grep_colors(&value));
+            }
+            for &(name, kind) in &[("colorpath", "path"), ("colorlineno", "line"),
+                                   ("colorspan", "match"), ("colorpunct", "separator")] {
+                if let Some(sgr) = m.value_of(name) {
+                    result = result.and_then(|_| specs.set_raw(kind, sgr));
+                }
+            }
+            for spec in m.values_of("colors").into_iter().flat_map(|v| v) {
+                result = result.and_then(|_| specs.apply_spec(spec));
+            }
+            if let Err(msg) = result {
+                Error::with_description(&msg, ErrorKind::InvalidValue).exit();
+            }
+            specs.to_colors()
+        };
+        let hyperlinks = Hyperlinks::new(m.value_of("hyperlinkformat").unwrap_or("file"))
+            .unwrap_or_else(|msg| Error::with_description(&msg, ErrorKind::InvalidValue).exit())
+            // links are only useful (and understood) in a terminal
+            .and_then(|links| if out_to_tty { Some(links) } else { None });
+        // the pager is only used for terminal output; it is started with the
+        // tty-based defaults determined here
+        let pager = if out_to_tty && m.is_present("pager") {
+            Some(m.value_of("pager").map(String::from)
+                 .or_else(|| env::var("PAGER").ok().and_then(|cmd| {
+                     if cmd.is_empty() { None } else { Some(cmd) }
+                 }))
+                 .unwrap_or_else(|| "less -RFX".into()))
+        } else {
+            None
         };
         let mut heading = out_to_tty;
         let mut showbreak = out_to_tty;
@@ -218,6 +458,14 @@ impl Opts {
         }
         let maxcount = m.value_of("maxcount").and_then(|v| v.parse().ok())
                                              .unwrap_or(usize::MAX);
+        let maxfiles = m.value_of("maxfiles").and_then(|v| v.parse().ok())
+                                             .unwrap_or(usize::MAX);
+        let maxmatches = m.value_of("maxmatches").and_then(|v| v.parse().ok())
+                                                 .unwrap_or(usize::MAX);
+        let maxcolumns = match m.value_of("maxcolumns").and_then(|v| v.parse().ok()) {
+            Some(0) => None,  // no limit
+            v => v,
+        };
         let mut before = m.value_of("before").and_then(|v| v.parse().ok())
                                              .unwrap_or(0);
         let mut after = m.value_of("after").and_then(|v| v.parse().ok())
@@ -227,6 +475,37 @@ impl Opts {
             after = before;
         }
 
+        let sort = match m.value_of("sort").or(m.value_of("sortr")) {
+            Some("none") => Sort::Unordered,
+            Some("path") => Sort::Path,
+            Some("modified") => Sort::Modified,
+            Some("accessed") => Sort::Accessed,
+            Some("created") => Sort::Created,
+            _ => Sort::Walk,
+        };
+
+        let patterns = |name| {
+            m.values_of(name).into_iter().flat_map(|v| v).map(String::from).collect()
+        };
+
+        // if given multiple times, the last one counts
+        let near = m.values_of("near").map(|values| {
+            let values = values.collect::<Vec<_>>();
+            let (distance, pattern) = (values[values.len() - 2], values[values.len() - 1]);
+            (distance.parse().unwrap_or_else(|_| {
+                Error::with_description(&format!("invalid line distance: {}", distance),
+                                        ErrorKind::InvalidValue).exit()
+            }), pattern.into())
+        });
+
+        // given in MiB
+        let jit_stack = m.value_of("pcre2jitstack").map(|v| {
+            v.parse::<usize>().ok().and_then(|v| v.checked_mul(1 << 20)).unwrap_or_else(|| {
+                Error::with_description(&format!("invalid JIT stack size: {}", v),
+                                        ErrorKind::InvalidValue).exit()
+            })
+        });
+
         let workers = m.value_of("workers").and_then(|v| v.parse().ok())
                                            .unwrap_or(min(4, num_cpus::get()));
 
@@ -235,17 +514,29 @@ impl Opts {
             path: m.value_of("path").unwrap_or(".").into(),
             depth: depth,
             follow_links: m.is_present("follow"),
-            do_binaries: binaries,
+            binaries: binaries,
             do_hidden: hidden,
+            encoding: encoding,
             // ignore file related
             check_ignores: ignores,
             // pattern related
             pattern: m.value_of("pattern").unwrap().into(),
+            and_patterns: patterns("and"),
+            or_patterns: patterns("or"),
+            not_patterns: patterns("not"),
+            near: near,
             casing: casing,
             literal: literal,
+            engine: engine,
+            pcre2_unicode: m.is_present("pcre2unicode"),
+            pcre2_jit_stack: jit_stack,
             invert: m.is_present("invert"),
+            null_data: m.is_present("nulldata"),
+            crlf: m.is_present("crlf"),
             // display related
             colors: Some(colors),
+            hyperlinks: hyperlinks,
+            pager: pager,
             only_files: if m.is_present("fileswith") {
                 Some(true)
             } else if m.is_present("fileswithout") {
@@ -256,7 +547,16 @@ impl Opts {
             show_heading: heading,
             ackmate_format: m.is_present("ackmate"),
             vimgrep_format: m.is_present("vimgrep"),
+            hexdump: m.is_present("hexdump"),
+            null_fnames: m.is_present("null"),
+            sort: sort,
+            sort_reverse: m.is_present("sortr"),
+            stats: m.is_present("stats"),
             max_count: maxcount,
+            max_files: maxfiles,
+            max_matches: maxmatches,
+            max_columns: maxcolumns,
+            max_columns_preview: m.is_present("maxcolumnspreview"),
             before: before,
             after: after,
             // other
@@ -0,0 +1,15 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::io;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// Start the pager command (using the shell) and return the process and its
+/// input, which is closed when the pager quits.
+pub fn spawn(cmd: &str) -> io::Result<(Child, ChildStdin)> {
+    let mut child = try!(Command::new("sh").arg("-c").arg(cmd).stdin(Stdio::piped()).spawn());
+    let stdin = child.stdin.take().unwrap();
+    Ok((child, stdin))
+}
@@ -3,370 +3,350 @@
 // Licensed under the MIT license.
 // ---------------------------------------------------------------------------------------
 
-// This file derived from rust-pcre:
-// Copyright 2015 The rust-pcre authors.
-//
-// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
-// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
-// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
-// option. This file may not be copied, modified, or distributed
-// except according to those terms.
-
-use std::ffi::{CStr, CString};
-use std::{fmt, ptr};
-use libc::{c_char, c_int, c_uchar, c_void};
+//! A minimal binding to the 8-bit library of PCRE2.
+
+use std::cmp::min;
+use std::{fmt, ptr, slice};
+use std::io::{stderr, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc::{c_int, c_void, size_t};
 
 mod ffi {
-    use libc::{c_char, c_int, c_uchar, c_ulong, c_void};
+    use libc::{c_int, c_void, size_t};
 
     #[allow(non_camel_case_types)]
-    pub type compile_options = c_int;
+    pub enum pcre2_code {}
     #[allow(non_camel_case_types)]
-    pub type exec_options = c_int;
+    pub enum pcre2_match_data {}
     #[allow(non_camel_case_types)]
-    pub type fullinfo_field = c_int;
+    pub enum pcre2_match_context {}
     #[allow(non_camel_case_types)]
-    pub type study_options = c_int;
+    pub enum pcre2_jit_stack {}
 
-    #[link(name = "pcre")]
+    // general and compile contexts are always passed as null, and so is the
+    // JIT stack callback (which makes PCRE2 use the stack given as data)
+    #[link(name = "pcre2-8")]
     extern {
-        pub static pcre_free: extern "C" fn(ptr: *mut c_void);
-
-        pub fn pcre_compile(pattern: *const c_char, options: compile_options,
-                            errptr: *mut *const c_char, erroffset: *mut c_int,
-                            tableptr: *const c_uchar) -> *mut pcre;
-        pub fn pcre_exec(code: *const pcre, extra: *const pcre_extra, subject: *const c_char,
-                         length: c_int, startoffset: c_int, options: exec_options,
-                         ovector: *mut c_int, ovecsize: c_int) -> c_int;
-        pub fn pcre_free_study(extra: *mut pcre_extra);
-        pub fn pcre_fullinfo(code: *const pcre, extra: *const pcre_extra, what: fullinfo_field,
-                             where_: *mut c_void) -> c_int;
-        pub fn pcre_study(code: *const pcre, options: study_options,
-                          errptr: *mut *const c_char) -> *mut pcre_extra;
+        pub fn pcre2_compile_8(pattern: *const u8, length: size_t, options: u32,
+                               errorcode: *mut c_int, erroroffset: *mut size_t,
+                               ccontext: *mut c_void) -> *mut pcre2_code;
+        pub fn pcre2_code_free_8(code: *mut pcre2_code);
+        pub fn pcre2_get_error_message_8(errorcode: c_int, buffer: *mut u8,
+                                         bufflen: size_t) -> c_int;
+        pub fn pcre2_jit_compile_8(code: *mut pcre2_code, options: u32) -> c_int;
+        pub fn pcre2_pattern_info_8(code: *const pcre2_code, what: u32,
+                                    where_: *mut c_void) -> c_int;
+
+        pub fn pcre2_match_data_create_from_pattern_8(code: *const pcre2_code,
+                                                      gcontext: *mut c_void)
+                                                      -> *mut pcre2_match_data;
+        pub fn pcre2_match_data_free_8(data: *mut pcre2_match_data);
+        pub fn pcre2_get_ovector_pointer_8(data: *mut pcre2_match_data) -> *mut size_t;
+        pub fn pcre2_match_8(code: *const pcre2_code, subject: *const u8, length: size_t,
+                             startoffset: size_t, options: u32, data: *mut pcre2_match_data,
+                             mcontext: *mut pcre2_match_context) -> c_int;
+
+        pub fn pcre2_match_context_create_8(gcontext: *mut c_void) -> *mut pcre2_match_context;
+        pub fn pcre2_match_context_free_8(mcontext: *mut pcre2_match_context);
+        pub fn pcre2_jit_stack_create_8(startsize: size_t, maxsize: size_t,
+                                        gcontext: *mut c_void) -> *mut pcre2_jit_stack;
+        pub fn pcre2_jit_stack_free_8(stack: *mut pcre2_jit_stack);
+        pub fn pcre2_jit_stack_assign_8(mcontext: *mut pcre2_match_context,
+                                        callback: *mut c_void, data: *mut c_void);
     }
 
-    // pub const PCRE_UTF8: compile_options = 0x00000800;
+    pub const PCRE2_CASELESS: u32 = 0x00000008;
+    pub const PCRE2_MULTILINE: u32 = 0x00000400;
+    pub const PCRE2_UCP: u32 = 0x00020000;
+    pub const PCRE2_UTF: u32 = 0x00080000;
+    pub const PCRE2_MATCH_INVALID_UTF: u32 = 0x04000000;
 
-    // PCRE_NO_UTF8_CHECK is both a compile and exec option
-    pub const PCRE_NO_UTF8_CHECK: c_int = 0x00002000;
+    pub const PCRE2_JIT_COMPLETE: u32 = 0x00000001;
 
-    pub const PCRE_ERROR_NOMATCH: c_int = -1;
-    pub const PCRE_ERROR_NULL: c_int = -2;
+    pub const PCRE2_INFO_CAPTURECOUNT: u32 = 4;
+    pub const PCRE2_INFO_NAMECOUNT: u32 = 17;
+    pub const PCRE2_INFO_NAMEENTRYSIZE: u32 = 18;
+    pub const PCRE2_INFO_NAMETABLE: u32 = 19;
 
-    pub const PCRE_INFO_CAPTURECOUNT: fullinfo_field = 2;
-    // pub const PCRE_INFO_NAMEENTRYSIZE: fullinfo_field = 7;
-    // pub const PCRE_INFO_NAMECOUNT: fullinfo_field = 8;
-    // pub const PCRE_INFO_NAMETABLE: fullinfo_field = 9;
+    pub const PCRE2_ERROR_NOMATCH: c_int = -1;
 
-    pub const PCRE_STUDY_JIT_COMPILE: c_int = 0x0001;
-    // pub const PCRE_STUDY_JIT_PARTIAL_SOFT_COMPILE: c_int = 0x0002;
-    // pub const PCRE_STUDY_JIT_PARTIAL_HARD_COMPILE: c_int = 0x0004;
-    // pub const PCRE_STUDY_EXTRA_NEEDED: c_int = 0x0008;
+    pub const PCRE2_UNSET: usize = !0;
+}
 
-    #[allow(non_camel_case_types)]
-    pub enum pcre {}
+/// Match letters case-insensitively.
+pub const CASELESS: u32 = ffi::PCRE2_CASELESS;
+/// Let ^ and $ match at line boundaries.
+pub const MULTILINE: u32 = ffi::PCRE2_MULTILINE;
+/// Treat pattern and subject as UTF-8, and use Unicode properties for \w, \d
+/// etc.  Invalid UTF-8 in the subject never matches.
+pub const UNICODE: u32 = ffi::PCRE2_UTF | ffi::PCRE2_UCP | ffi::PCRE2_MATCH_INVALID_UTF;
 
-    #[allow(non_camel_case_types)]
-    #[repr(C)]
-    pub struct pcre_extra {
-        flags: c_ulong,
-        study_data: *mut c_void,
-        match_limit: c_ulong,
-        callout_data: *mut c_void,
-        tables: *const c_uchar,
-        match_limit_recursion_: c_ulong,
-        mark: *mut *mut c_uchar,
-        executable_jit: *mut c_void
-    }
-}
+/// Initial size of a custom JIT stack.
+const JIT_STACK_START: usize = 32 * 1024;
 
-pub unsafe fn pcre_compile(pattern: *const c_char, options: ffi::compile_options,
-                           tableptr: *const c_uchar) -> Result<*mut ffi::pcre, (String, c_int)> {
-    assert!(!pattern.is_null());
-    // the pattern is always UTF-8
-    let options = options | ffi::PCRE_NO_UTF8_CHECK;
-    let mut err: *const c_char = ptr::null();
-    let mut erroffset: c_int = 0;
-    let code = ffi::pcre_compile(pattern, options, &mut err, &mut erroffset, tableptr);
-
-    if code.is_null() {
-        // "Otherwise, if  compilation  of  a  pattern fails, pcre_compile() returns
-        // NULL, and sets the variable pointed to by errptr to point to a textual
-        // error message. This is a static string that is part of the library. You
-        // must not try to free it."
-        Err((CStr::from_ptr(err).to_string_lossy().into_owned(), erroffset))
-    } else {
-        assert!(!code.is_null());
-        assert_eq!(erroffset, 0);
-        Ok(code)
-    }
-}
+/// An error while compiling a pattern, with the offset in the pattern.
+#[derive(Debug)]
+pub struct CompilationError(String, usize);
 
-pub unsafe fn pcre_exec(code: *const ffi::pcre, extra: *const ffi::pcre_extra,
-                        subject: *const c_char, length: c_int, startoffset: c_int,
-                        options: ffi::compile_options,
-                        ovector: *mut c_int, ovecsize: c_int) -> Result<c_int, ()> {
-    assert!(!code.is_null());
-    assert!(ovecsize >= 0 && ovecsize % 3 == 0);
-    let options = options | ffi::PCRE_NO_UTF8_CHECK;
-    let rc = ffi::pcre_exec(code, extra, subject, length, startoffset, options, ovector, ovecsize);
-    if rc == ffi::PCRE_ERROR_NOMATCH {
-        Ok(-1)
-    } else if rc < 0 {
-        Err(())
-    } else {
-        Ok(rc)
+impl fmt::Display for CompilationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.0, self.1)
     }
 }
 
-pub unsafe fn pcre_free(ptr: *mut c_void) {
-    ffi::pcre_free(ptr);
-}
-
-pub unsafe fn pcre_free_study(extra: *mut ffi::pcre_extra) {
-    ffi::pcre_free_study(extra);
+/// The per-thread state needed for matching.
+struct Scratch {
+    data: *mut ffi::pcre2_match_data,
+    context: *mut ffi::pcre2_match_context,
+    stack: *mut ffi::pcre2_jit_stack,
 }
 
-pub unsafe fn pcre_fullinfo(code: *const ffi::pcre, extra: *const ffi::pcre_extra,
-                            what: ffi::fullinfo_field, where_: *mut c_void) {
-    assert!(!code.is_null());
-    let rc = ffi::pcre_fullinfo(code, extra, what, where_);
-    if rc < 0 && rc != ffi::PCRE_ERROR_NULL {
-        panic!("pcre_fullinfo");
-    }
-}
+/// Scratch space is only used by one thread at a time.
+unsafe impl Send for Scratch {}
 
-pub unsafe fn pcre_study(code: *const ffi::pcre, options: ffi::study_options)
-                         -> Result<*mut ffi::pcre_extra, String> {
-    assert!(!code.is_null());
-    let converted_options = options;
-    let mut err: *const c_char = ptr::null();
-    let extra = ffi::pcre_study(code, converted_options, &mut err);
-    // "The third argument for pcre_study() is a pointer for an error message. If
-    // studying succeeds (even if no data is returned), the variable it points to is
-    // set to NULL. Otherwise it is set to point to a textual error message. This is
-    // a static string that is part of the library. You must not try to free it."
-    // http://pcre.org/pcre.txt
-    if !err.is_null() {
-        Err(CStr::from_ptr(err).to_string_lossy().into_owned())
-    } else {
-        assert!(err.is_null());
-        Ok(extra)
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::pcre2_match_data_free_8(self.data);
+            if !self.context.is_null() {
+                ffi::pcre2_match_context_free_8(self.context);
+            }
+            if !self.stack.is_null() {
+                ffi::pcre2_jit_stack_free_8(self.stack);
+            }
+        }
     }
 }
 
-pub type Pcre = ffi::pcre;
-pub type PcreExtra = ffi::pcre_extra;
-pub type CompileOptions = ffi::compile_options;
-pub type ExecOptions = ffi::exec_options;
-pub type StudyOptions = ffi::study_options;
-
-/// Wrapper for libpcre's `pcre` object (representing a compiled regular expression).
-#[derive(Debug)]
+/// Wrapper for PCRE2's `pcre2_code` object (representing a compiled regular
+/// expression).
 pub struct Regex {
-    code: *const Pcre,
-    extra: *mut PcreExtra,
-    capture_count: c_int,
-}
-
-/// Represents a match of a subject string against a regular expression.
-#[allow(unused)]
-pub struct Match<'s> {
-    subject: &'s [u8],
-    partial_ovector: Vec<c_int>,
-    string_count: c_int
-}
-
-/// Iterator type for iterating matches within a subject string.
-pub struct MatchIterator<'r, 's> {
-    regex: &'r Regex,
-    subject: &'s [u8],
-    offset: c_int,
-    options: ExecOptions,
-    ovector: Vec<c_int>
-}
-
-#[derive(Debug)]
-pub struct CompilationError(String, c_int);
-
-impl fmt::Display for CompilationError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "compilation failed at offset {}: {}", self.1, self.0)
-    }
+    code: *mut ffi::pcre2_code,
+    capture_count: usize,
+    /// Names of the groups, by number
+    names: Vec<Option<String>>,
+    /// Maximum size of the JIT stack, if not the default
+    jit_stack: Option<usize>,
+    /// Scratch space for matching, reused between matches
+    scratch: Mutex<Vec<Scratch>>,
+    /// Set once a matching error has been reported
+    warned: AtomicBool,
 }
 
 impl Regex {
-
-    pub fn new(pattern: &str) -> Result<Regex, CompilationError> {
-        Regex::compile_with_options(pattern, 0).map(|mut rx| {
-            rx.study_with_options(ffi::PCRE_STUDY_JIT_COMPILE);
-            rx
+    /// Compile the pattern with the given options (CASELESS, MULTILINE and
+    /// UNICODE), and JIT-compile it if possible.  `jit_stack` is the maximum
+    /// size of the JIT stack, the default is 32 KiB.
+    pub fn new(pattern: &str, options: u32, jit_stack: Option<usize>)
+               -> Result<Regex, CompilationError> {
+        let mut errorcode: c_int = 0;
+        let mut erroroffset: size_t = 0;
+        let code = unsafe {
+            ffi::pcre2_compile_8(pattern.as_ptr(), pattern.len(), options,
+                                 &mut errorcode, &mut erroroffset, ptr::null_mut())
+        };
+        if code.is_null() {
+            return Err(CompilationError(error_message(errorcode), erroroffset));
+        }
+        let mut capture_count: u32 = 0;
+        unsafe {
+            // if JIT is not available, the interpreter is used
+            ffi::pcre2_jit_compile_8(code, ffi::PCRE2_JIT_COMPLETE);
+            ffi::pcre2_pattern_info_8(code, ffi::PCRE2_INFO_CAPTURECOUNT,
+                                      &mut capture_count as *mut u32 as *mut c_void);
+        }
+        Ok(Regex {
+            code: code,
+            capture_count: capture_count as usize,
+            names: group_names(code, capture_count as usize),
+            jit_stack: jit_stack,
+            scratch: Mutex::new(Vec::new()),
+            warned: AtomicBool::new(false),
         })
     }
 
-    pub fn compile_with_options(pattern: &str, options: CompileOptions)
-                                -> Result<Regex, CompilationError> {
-        let pattern_cstring = CString::new(pattern).unwrap();
-        // Use the default character tables.
-        let tableptr: *const c_uchar = ptr::null();
-        match unsafe { pcre_compile(pattern_cstring.as_ptr(), options, tableptr) } {
-            Err((errstr, offset)) => Err(CompilationError(errstr, offset)),
-            Ok(mut_code) => {
-                let code = mut_code as *const Pcre;
-                assert!(!code.is_null());
-
-                // Default extra is null.
-                let extra: *mut PcreExtra = ptr::null_mut();
-                let mut capture_count: c_int = 0;
-                unsafe {
-                    pcre_fullinfo(code, extra as *const PcreExtra, ffi::PCRE_INFO_CAPTURECOUNT,
-                                  &mut capture_count as *mut c_int as *mut c_void);
-                }
-
-                Ok(Regex {
-                    code: code,
-                    extra: extra,
-                    capture_count: capture_count,
-                })
+    fn new_scratch(&self) -> Scratch {
+        let mut scratch = Scratch {
+            data: unsafe { ffi::pcre2_match_data_create_from_pattern_8(self.code,
+                                                                        ptr::null_mut()) },
+            context: ptr::null_mut(),
+            stack: ptr::null_mut(),
+        };
+        assert!(!scratch.data.is_null(), "out of memory");
+        if let Some(size) = self.jit_stack {
+            unsafe {
+                scratch.context = ffi::pcre2_match_context_create_8(ptr::null_mut());
+                scratch.stack = ffi::pcre2_jit_stack_create_8(min(JIT_STACK_START, size), size,
+                                                              ptr::null_mut());
+                assert!(!scratch.context.is_null() && !scratch.stack.is_null(),
+                        "out of memory");
+                ffi::pcre2_jit_stack_assign_8(scratch.context, ptr::null_mut(),
+                                              scratch.stack as *mut c_void);
             }
         }
+        scratch
     }
 
-    #[inline]
-    pub fn exec<'a>(&self, subject: &'a [u8]) -> Option<Match<'a>> {
-        self.exec_from(subject, 0)
-    }
-
-    #[inline]
-    pub fn exec_from<'a>(&self, subject: &'a [u8], startoffset: usize) -> Option<Match<'a>> {
-        self.exec_from_with_options(subject, startoffset, 0)
-    }
-
-    #[inline]
-    pub fn exec_from_with_options<'a>(&self, subject: &'a [u8], startoffset: usize,
-                                      options: ExecOptions) -> Option<Match<'a>> {
-        let ovecsize = (self.capture_count + 1) * 3;
-        let mut ovector = vec![0 as c_int; ovecsize as usize];
-
+    /// Find the first match at or after `start`, and give the spans of all
+    /// groups to `func`.
+    fn exec<T, F>(&self, subject: &[u8], start: usize, func: F) -> Option<T>
+        where F: FnOnce(&[Option<(usize, usize)>]) -> T
+    {
+        if start > subject.len() {
+            return None;
+        }
+        let scratch = self.scratch.lock().unwrap().pop().unwrap_or_else(|| self.new_scratch());
         let rc = unsafe {
-            pcre_exec(self.code,
-                      self.extra as *const PcreExtra,
-                      subject.as_ptr() as *const c_char,
-                      subject.len() as c_int,
-                      startoffset as c_int,
-                      options,
-                      ovector.as_mut_ptr(),
-                      ovecsize as c_int)
+            ffi::pcre2_match_8(self.code, subject.as_ptr(), subject.len(), start, 0,
+                               scratch.data, scratch.context)
         };
-        match rc {
-            Ok(rc) if rc >= 0 => {
-                Some(Match {
-                    subject: subject,
-                    partial_ovector: ovector[..(((self.capture_count + 1) * 2) as usize)].to_vec(),
-                    string_count: rc
-                })
+        let result = if rc > 0 {
+            let ovector = unsafe { ffi::pcre2_get_ovector_pointer_8(scratch.data) };
+            let spans = (0..self.capture_count + 1).map(|i| {
+                let (start, end) = unsafe { (*ovector.offset(2 * i as isize),
+                                             *ovector.offset(2 * i as isize + 1)) };
+                if i >= rc as usize || start == ffi::PCRE2_UNSET {
+                    None
+                } else {
+                    Some((start, end))
+                }
+            }).collect::<Vec<_>>();
+            Some(func(&spans))
+        } else {
+            // errors such as an exceeded JIT stack are reported (once), and
+            // the subject is treated as not matching
+            if rc != ffi::PCRE2_ERROR_NOMATCH && !self.warned.swap(true, Ordering::Relaxed) {
+                let _ = writeln!(stderr(), "ru: PCRE2 matching error: {} (further errors \
+                                            are not reported)", error_message(rc));
             }
-            _ => { None }
-        }
+            None
+        };
+        self.scratch.lock().unwrap().push(scratch);
+        result
     }
 
-    #[inline]
-    pub fn find(&self, subject: &[u8]) -> Option<(usize, usize)> {
-        self.exec(subject).map(|m| m.group_span(0))
+    /// Find the first match at or after `start`.
+    pub fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        self.exec(subject, start, |spans| spans[0].unwrap())
     }
 
-    #[inline]
-    pub fn is_match(&self, subject: &[u8]) -> bool {
-        self.exec(subject).is_some()
+    /// Find the first match and return the spans of all groups.
+    pub fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        self.exec(subject, 0, |spans| spans.to_vec())
     }
 
-    pub fn study_with_options(&mut self, options: StudyOptions) -> bool {
-        let extra = unsafe {
-            // Free any current study data.
-            pcre_free_study(self.extra as *mut PcreExtra);
-            self.extra = ptr::null_mut();
-            pcre_study(self.code, options)
-        };
-        match extra {
-            Ok(extra) => {
-                self.extra = extra;
-                !extra.is_null()
-            }
-            Err(_) => false
-        }
+    /// Get the names of all groups (None for unnamed groups), including
+    /// group 0 which never has a name.
+    pub fn capture_names(&self) -> &[Option<String>] {
+        &self.names
+    }
+
+    /// Get the number of a named group.
+    pub fn capture_index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n.as_ref().map_or(false, |n| n == name))
     }
 }
 
 impl Drop for Regex {
     fn drop(&mut self) {
         unsafe {
-            pcre_free_study(self.extra as *mut PcreExtra);
-            pcre_free(self.code as *mut Pcre as *mut c_void);
+            ffi::pcre2_code_free_8(self.code);
         }
-        self.extra = ptr::null_mut();
-        self.code = ptr::null();
     }
 }
 
-impl<'a> Match<'a> {
-    pub fn group_start(&self, n: usize) -> usize {
-        self.partial_ovector[(n * 2) as usize] as usize
+/// Read-only access is guaranteed to be thread-safe.
+unsafe impl Sync for Regex {}
+/// The compiled pattern isn't tied to the thread that created it.
+unsafe impl Send for Regex {}
+
+/// Read the names of the groups from the name table of a compiled pattern.
+///
+/// Each entry of the table consists of the group number (two bytes, big
+/// endian) and the NUL-terminated name, padded to the size of the longest
+/// entry.
+fn group_names(code: *const ffi::pcre2_code, capture_count: usize) -> Vec<Option<String>> {
+    let mut names = vec![None; capture_count + 1];
+    let mut count: u32 = 0;
+    let mut entry_size: u32 = 0;
+    let mut table: *const u8 = ptr::null();
+    unsafe {
+        ffi::pcre2_pattern_info_8(code, ffi::PCRE2_INFO_NAMECOUNT,
+                                  &mut count as *mut u32 as *mut c_void);
+        ffi::pcre2_pattern_info_8(code, ffi::PCRE2_INFO_NAMEENTRYSIZE,
+                                  &mut entry_size as *mut u32 as *mut c_void);
+        ffi::pcre2_pattern_info_8(code, ffi::PCRE2_INFO_NAMETABLE,
+                                  &mut table as *mut *const u8 as *mut c_void);
     }
-
-    pub fn group_end(&self, n: usize) -> usize {
-        self.partial_ovector[(n * 2 + 1) as usize] as usize
+    if count == 0 || table.is_null() {
+        return names;
     }
-
-    pub fn group_span(&self, n: usize) -> (usize, usize) {
-        (self.group_start(n), self.group_end(n))
+    let table = unsafe { slice::from_raw_parts(table, (count * entry_size) as usize) };
+    for entry in table.chunks(entry_size as usize) {
+        let group = (entry[0] as usize) << 8 | entry[1] as usize;
+        let name = &entry[2..];
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        if group < names.len() {
+            names[group] = Some(String::from_utf8_lossy(&name[..len]).into_owned());
+        }
     }
+    names
 }
 
-impl<'r, 's> Clone for MatchIterator<'r, 's> {
-    #[inline]
-    fn clone(&self) -> MatchIterator<'r, 's> {
-        MatchIterator {
-            regex: self.regex,
-            subject: self.subject,
-            offset: self.offset,
-            options: self.options.clone(),
-            ovector: self.ovector.clone()
-        }
+/// Get the message for a PCRE2 error code.
+fn error_message(code: c_int) -> String {
+    let mut buf = [0u8; 256];
+    let len = unsafe { ffi::pcre2_get_error_message_8(code, buf.as_mut_ptr(), buf.len()) };
+    if len < 0 {
+        format!("unknown error {}", code)
+    } else {
+        String::from_utf8_lossy(&buf[..len as usize]).into_owned()
     }
 }
 
-impl<'r, 's> Iterator for MatchIterator<'r, 's> {
-    type Item = Match<'s>;
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::Regex;
+
+    #[test]
+    fn group_names() {
+        let rx = Regex::new(r"(?<year>\d{4})-(\d\d)-(?<d>\d\d)", 0, None).unwrap();
+        assert_eq!(rx.capture_names(),
+                   &[None, Some("year".into()), None, Some("d".into())]);
+        assert_eq!(rx.capture_index("year"), Some(1));
+        assert_eq!(rx.capture_index("d"), Some(3));
+        assert_eq!(rx.capture_index("month"), None);
+        assert_eq!(rx.captures(b"on 2016-01-31."),
+                   Some(vec![Some((3, 13)), Some((3, 7)), Some((8, 10)), Some((11, 13))]));
+    }
 
-    /// Gets the next match.
-    #[inline]
-    fn next(&mut self) -> Option<Match<'s>> {
-        let rc = unsafe {
-            pcre_exec(self.regex.code,
-                      self.regex.extra,
-                      self.subject.as_ptr() as *const c_char,
-                      self.subject.len() as c_int,
-                      self.offset,
-                      self.options,
-                      self.ovector.as_mut_ptr(),
-                      self.ovector.len() as c_int)
-        };
-        match rc {
-            Ok(rc) if rc >= 0 => {
-                // Update the iterator state.
-                self.offset = self.ovector[1];
-
-                let cc = self.regex.capture_count;
-                Some(Match {
-                    subject: self.subject,
-                    partial_ovector: self.ovector[..(((cc + 1) * 2) as usize)].to_vec(),
-                    string_count: rc
-                })
-            }
-            _ => None
-        }
+    #[test]
+    fn no_group_names() {
+        let rx = Regex::new(r"(a)(b)", 0, None).unwrap();
+        assert_eq!(rx.capture_names(), &[None, None, None]);
+        assert_eq!(rx.capture_index("a"), None);
     }
-}
 
-/// Read-only access is guaranteed to be thread-safe.
-unsafe impl Sync for Regex {}
+    #[test]
+    fn group_names_above_255() {
+        // the group number is stored in two bytes
+        let pattern = format!("{}(?<last>b)", (0..299).map(|_| "(a)").collect::<String>());
+        let rx = Regex::new(&pattern, 0, None).unwrap();
+        assert_eq!(rx.capture_names().len(), 301);
+        assert_eq!(rx.capture_index("last"), Some(300));
+    }
+
+    #[test]
+    fn match_error() {
+        // running out of JIT stack is an error, not "no match"
+        // (the start optimizations would see that there is no "x")
+        let rx = Regex::new(r"(*NO_START_OPT)(?:(a)|b)*x", 0, Some(1)).unwrap();
+        let subject = "ab".repeat(100000);
+        assert_eq!(rx.find_at(subject.as_bytes(), 0), None);
+        assert!(rx.warned.load(Ordering::Relaxed));
+        assert_eq!(rx.find_at(b"abx", 0), Some((0, 3)));
+        assert_eq!(rx.find_at(b"abx", 4), None);
+    }
+}
@@ -0,0 +1,147 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use matcher::Matcher;
+
+/// A query on whole files (or other subjects), made of several patterns.
+///
+/// A file satisfies the query if it matches the main pattern or one of the
+/// `or` patterns, all `and` patterns and none of the `not` patterns.  The
+/// matches of all these positive patterns are reported.
+///
+/// With a `near` pattern, matches are only reported in lines that have a
+/// match of that pattern within the given number of lines (see `search`).
+///
+/// As a `Matcher`, a query finds the matches of all positive patterns, but
+/// doesn't check the query itself: that is what `accepts` is for.
+pub struct Query {
+    /// The main and `or` patterns
+    any: Vec<Box<Matcher>>,
+    /// The `and` patterns
+    all: Vec<Box<Matcher>>,
+    /// The `not` patterns
+    none: Vec<Box<Matcher>>,
+    /// The `near` pattern and its maximum distance in lines
+    near: Option<(usize, Box<Matcher>)>,
+}
+
+impl Query {
+    /// Create a query for the main pattern.
+    pub fn new(main: Box<Matcher>) -> Query {
+        Query { any: vec![main], all: Vec::new(), none: Vec::new(), near: None }
+    }
+
+    /// Add a pattern that must also match.
+    pub fn and(mut self, matcher: Box<Matcher>) -> Query {
+        self.all.push(matcher);
+        self
+    }
+
+    /// Add a pattern that can match instead of the main pattern.
+    pub fn or(mut self, matcher: Box<Matcher>) -> Query {
+        self.any.push(matcher);
+        self
+    }
+
+    /// Add a pattern that must not match.
+    pub fn not(mut self, matcher: Box<Matcher>) -> Query {
+        self.none.push(matcher);
+        self
+    }
+
+    /// Set a pattern that must match at most `distance` lines away from
+    /// reported lines.
+    pub fn near(mut self, distance: usize, matcher: Box<Matcher>) -> Query {
+        self.near = Some((distance, matcher));
+        self
+    }
+
+    /// Get the `near` pattern and its distance, if there is one.
+    pub fn near_matcher(&self) -> Option<(usize, &Matcher)> {
+        self.near.as_ref().map(|&(distance, ref m)| (distance, &**m))
+    }
+
+    /// Check if a subject satisfies the query.  `is_match` tells whether a
+    /// single pattern matches in it.
+    pub fn accepts<F: FnMut(&Matcher) -> bool>(&self, mut is_match: F) -> bool {
+        self.all.iter().all(|m| is_match(&**m)) &&
+            self.any.iter().any(|m| is_match(&**m)) &&
+            !self.none.iter().any(|m| is_match(&**m))
+    }
+
+    /// Get the positive patterns, whose matches are reported.
+    pub fn positive(&self) -> Vec<&Matcher> {
+        self.any.iter().chain(&self.all).map(|m| &**m).collect()
+    }
+
+    /// Get the index (in `positive()`) and span of the first match of any
+    /// positive pattern; of several matches at the same offset, the longest
+    /// one is taken.
+    fn first_match(&self, subject: &[u8], start: usize) -> Option<(usize, (usize, usize))> {
+        let mut best: Option<(usize, (usize, usize))> = None;
+        for (i, m) in self.positive().into_iter().enumerate() {
+            if let Some((s, e)) = m.find_at(subject, start) {
+                if best.map_or(true, |(_, (bs, be))| s < bs || (s == bs && e > be)) {
+                    best = Some((i, (s, e)));
+                }
+            }
+        }
+        best
+    }
+}
+
+impl Matcher for Query {
+    fn find_at(&self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        self.first_match(subject, start).map(|(_, span)| span)
+    }
+
+    /// Capture groups are those of the main pattern.
+    fn captures(&self, subject: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        self.any[0].captures(subject)
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        self.any[0].capture_names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+    use matcher::{new_regex, Flags, Matcher};
+
+    fn rx(pattern: &str) -> Box<Matcher> {
+        new_regex(pattern, Flags::default()).unwrap()
+    }
+
+    #[test]
+    fn find() {
+        let query = Query::new(rx("b+")).or(rx("a")).and(rx("x")).not(rx("y"));
+        assert_eq!(query.find(b"cabbb"), Some((1, 2)));
+        assert_eq!(query.find_at(b"cabbb", 2), Some((2, 5)));
+        assert_eq!(query.find(b"cdef"), None);
+        // the same as find, whether the query is satisfied or not
+        assert!(query.is_match(b"ab"));
+        assert!(!query.is_match(b"cdef"));
+    }
+
+    #[test]
+    fn accepts() {
+        let query = Query::new(rx("a")).or(rx("b")).and(rx("x")).not(rx("y"));
+        let check = |subject: &[u8]| query.accepts(|m| m.is_match(subject));
+        assert!(check(b"ax"));
+        assert!(check(b"bx"));
+        assert!(!check(b"ab"));
+        assert!(!check(b"axy"));
+    }
+
+    #[test]
+    fn captures() {
+        let query = Query::new(rx("(?P<n>a)(c)?")).or(rx("(b)"));
+        assert_eq!(query.capture_names(), vec![None, Some("n".into()), None]);
+        assert_eq!(query.captures(b"bac"), Some(vec![Some((1, 3)), Some((1, 2)), Some((2, 3))]));
+        assert_eq!(query.captures(b"bb"), None);
+    }
+}
@@ -3,25 +3,35 @@
 // Licensed under the MIT license.
 // ---------------------------------------------------------------------------------------
 
+use std::borrow::Cow;
 use std::cmp::min;
 use std::path::Path;
+use std::sync::Arc;
+use std::{ptr, str};
+use memchr::{memchr, memrchr};
 
-#[cfg(feature = "pcre")]
-use pcre::Regex;
-#[cfg(not(feature = "pcre"))]
-use regex::bytes::Regex;
-
-use options::{Casing, Opts};
+use literals::Prefiltered;
+use matcher::{self, Flags, Matcher};
+use options::{Binaries, Casing, Engine, Opts};
+use query::Query;
 
 /// Represents a line that matched the pattern (maybe multiple times).
+///
+/// This is part of the stable library API: the fields don't change within a
+/// minor version, new fields are only added with a new major version.
 #[derive(Debug)]
 pub struct Match {
-    /// Line number in the file
+    /// Line number in the file (0 for matches in binary files)
     pub lineno: usize,
+    /// Byte offset of the line (or excerpt, for binary files) in the file
+    pub offset: usize,
     /// Line text
     pub line: Vec<u8>,
     /// Spans (start, end) of matching parts in the line
     pub spans: Vec<(usize, usize)>,
+    /// Spans of the `--near` pattern that don't overlap `spans`, which are
+    /// shown but not counted
+    pub near: Vec<(usize, usize)>,
     /// Context lines before the matched line
     pub before: Vec<Vec<u8>>,
     /// Context lines after the matched line
@@ -29,11 +39,35 @@ pub struct Match {
 }
 
 impl Match {
+    /// Get the number of matches in the line, without those of the `--near`
+    /// pattern.
+    pub fn count(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Check if the line is only reported because of the `--near` pattern.
+    pub fn is_near_only(&self) -> bool {
+        self.spans.is_empty() && !self.near.is_empty()
+    }
+
+    /// Get the spans of the matches and of the `--near` pattern, in order.
+    pub fn all_spans(&self) -> Cow<[(usize, usize)]> {
+        if self.near.is_empty() {
+            return Cow::Borrowed(&self.spans);
+        }
+        let mut spans = self.spans.clone();
+        spans.extend_from_slice(&self.near);
+        spans.sort();
+        Cow::Owned(spans)
+    }
+
     fn new(lineno: usize, line: Vec<u8>, spans: Vec<(usize, usize)>) -> Match {
         Match {
             lineno: lineno,
+            offset: 0,
             line: line,
             spans: spans,
+            near: Vec::new(),
             before: Vec::new(),
             after: Vec::new(),
         }
@@ -41,14 +75,19 @@ impl Match {
 }
 
 /// Represents all matches from a single file.
+///
+/// This is part of the stable library API, like `Match`.
 #[derive(Debug)]
 pub struct FileResult {
     /// File name, relative to initial argument
     pub fname: String,
-    /// Is the file binary?  If yes, matches contains 0 or 1 element
+    /// Is the file binary?  If yes, matches contains 0 or 1 element, unless
+    /// binary matches are requested with excerpts (then, one per match)
     pub is_binary: bool,
     /// Do we provide (and print) context lines?
     pub has_context: bool,
+    /// Size of the searched (maybe transcoded) file contents
+    pub size: usize,
     /// Matches relevant for printing
     pub matches: Vec<Match>,
 }
@@ -59,17 +98,77 @@ impl FileResult {
             fname: fname,
             is_binary: false,
             has_context: false,
+            size: 0,
             matches: Vec::new(),
         }
     }
 }
 
-/// Create a regular expression to search for matches from the given options.
+/// Create a matcher to search for matches from the given options.
+///
+/// This is a regex for the main pattern, or a `Query` if there are also
+/// `--and`, `--or`, `--not` or `--near` patterns.  The query is then returned
+/// by itself as well, to be given to `search`.  The last return value is a
+/// note for the user, see `compile`.
+pub fn create_rx(opts: &Opts)
+                 -> Result<(Arc<Matcher>, Option<Arc<Query>>, Option<String>), String> {
+    let (main, note) = try!(compile(&opts.pattern, opts));
+    add_patterns(main, note, opts)
+}
+
+/// Like `create_rx`, but with an already compiled matcher for the main
+/// pattern.  The other patterns are still compiled from the options.
+pub fn create_query(main: Box<Matcher>, opts: &Opts)
+                    -> Result<(Arc<Matcher>, Option<Arc<Query>>), String> {
+    add_patterns(main, None, opts).map(|(matcher, query, _)| (matcher, query))
+}
+
+/// Combine the main matcher with the other patterns from the options.
+fn add_patterns(main: Box<Matcher>, mut note: Option<String>, opts: &Opts)
+                -> Result<(Arc<Matcher>, Option<Arc<Query>>, Option<String>), String> {
+    if opts.and_patterns.is_empty() && opts.or_patterns.is_empty() &&
+        opts.not_patterns.is_empty() && opts.near.is_none()
+    {
+        return Ok((Arc::from(main), None, note));
+    }
+    let extra = |pattern: &String| {
+        compile(pattern, opts).map_err(|err| format!("{:?}: {}", pattern, err))
+    };
+    let mut query = Query::new(main);
+    for pattern in &opts.and_patterns {
+        let (matcher, extra_note) = try!(extra(pattern));
+        query = query.and(matcher);
+        note = note.or(extra_note);
+    }
+    for pattern in &opts.or_patterns {
+        let (matcher, extra_note) = try!(extra(pattern));
+        query = query.or(matcher);
+        note = note.or(extra_note);
+    }
+    for pattern in &opts.not_patterns {
+        let (matcher, extra_note) = try!(extra(pattern));
+        query = query.not(matcher);
+        note = note.or(extra_note);
+    }
+    if let Some((distance, ref pattern)) = opts.near {
+        let (matcher, extra_note) = try!(extra(pattern));
+        query = query.near(distance, matcher);
+        note = note.or(extra_note);
+    }
+    let query = Arc::new(query);
+    Ok((query.clone(), Some(query), note))
+}
+
+/// Create a regular expression for one pattern from the given options.
 ///
 /// The final regex is determined by several options, such as casing options
-/// and options to take the search string literally.
-pub fn create_rx(opts: &Opts) -> Regex {
-    let mut pattern = opts.pattern.to_owned();
+/// and options to take the search string literally, and the engine is chosen
+/// by the `engine` option.
+///
+/// If the Rust regex engine rejects the pattern, PCRE is tried instead (if it
+/// is compiled in); the second return value is then a note for the user.
+fn compile(source: &str, opts: &Opts) -> Result<(Box<Matcher>, Option<String>), String> {
+    let mut pattern = source.to_owned();
     if opts.literal {
         // escape regex meta-chars and create a normal pattern
         const ESCAPE: &'static str = ".?*+|^$(){}[]\\";
@@ -81,15 +180,52 @@ pub fn create_rx(opts: &Opts) -> Regex {
             }
         }).collect();
     }
-    if let Casing::Insensitive = opts.casing {
-        pattern = format!("(?i){}", pattern);
-    } else if let Casing::Smart = opts.casing {
-        // smart casing: only case-insensitive when pattern contains no uppercase
-        if !pattern.chars().any(|c| c.is_uppercase()) {
-            pattern = format!("(?i){}", pattern);
+    let flags = Flags {
+        // let ^ and $ match at line boundaries (in record mode, each record is
+        // matched separately, so they match at its start and end anyway)
+        multiline: !opts.null_data && !opts.crlf,
+        caseless: match opts.casing {
+            Casing::Insensitive => true,
+            // smart casing: only case-insensitive when pattern contains no uppercase
+            Casing::Smart => !pattern.chars().any(|c| c.is_uppercase()),
+            Casing::Default => false,
+        },
+        unicode: opts.pcre2_unicode,
+        jit_stack: opts.pcre2_jit_stack,
+    };
+    let use_pcre = match opts.engine {
+        // the pattern is always taken literally
+        Engine::Fuzzy(max_dist) => {
+            return matcher::new_fuzzy(source, max_dist, flags).map(|m| (m, None));
+        }
+        Engine::Regex => false,
+        Engine::Pcre => true,
+        // the regex engine would take e.g. backreferences as octal escapes
+        Engine::Auto if matcher::needs_pcre(&pattern) => {
+            if !matcher::has_pcre() {
+                return Err("backreferences and lookaround need PCRE, which is not \
+                            compiled in (build with the \"pcre\" feature)".into());
+            }
+            true
+        }
+        Engine::Auto => false,
+    };
+    if use_pcre {
+        return matcher::new_pcre(&pattern, flags)
+            .map(|rx| (Prefiltered::wrap(rx, &pattern, flags), None));
+    }
+    match matcher::new_regex(&pattern, flags) {
+        Ok(rx) => Ok((Prefiltered::wrap(rx, &pattern, flags), None)),
+        Err(err) => {
+            if !matcher::has_pcre() {
+                return Err(err);
+            }
+            // PCRE supports more syntax, e.g. backreferences; if it doesn't
+            // like the pattern either, the first error is more relevant
+            let note = "pattern not supported by the regex engine, using PCRE".into();
+            matcher::new_pcre(&pattern, flags).map(|rx| (rx, Some(note))).map_err(|_| err)
         }
     }
-    Regex::new(&pattern).unwrap()
 }
 
 /// Return normalized path: get rid of leading ./ and make leading // into /.
@@ -104,11 +240,58 @@ fn normalized_path(path: &Path) -> String {
     }
 }
 
+/// Number of bytes at the start of a file that are checked for binary-ness.
+const BINARY_CHECK_LEN: usize = 1024;
+
+/// Magic numbers at the start of common binary file formats.
+const BINARY_MAGIC: &'static [&'static [u8]] = &[
+    b"\x7FELF",                 // ELF executables and libraries
+    b"\x89PNG\r\n\x1A\n",       // PNG images
+    b"GIF87a", b"GIF89a",       // GIF images
+    b"\xFF\xD8\xFF",            // JPEG images
+    b"PK\x03\x04",              // zip archives (also jar, docx, ...)
+    b"\x1F\x8B",                // gzip
+    b"\xFD7zXZ\x00",            // xz
+    b"7z\xBC\xAF\x27\x1C",      // 7-zip
+    b"%PDF-",                   // PDF documents
+    b"\xCA\xFE\xBA\xBE",        // Java classes and Mach-O fat binaries
+    b"\xCF\xFA\xED\xFE",        // 64-bit Mach-O
+    b"\x00asm",                 // WebAssembly modules
+];
+
+/// Count bytes that are unlikely to appear in text: control characters other
+/// than the usual whitespace, and bytes that are not valid UTF-8.
+fn count_suspicious(buf: &[u8]) -> usize {
+    let mut count = buf.iter().filter(|&&b| {
+        (b < 0x20 && !b"\t\n\r\x0C\x1B\x08".contains(&b)) || b == 0x7F
+    }).count();
+    let mut rest = buf;
+    while let Err(e) = str::from_utf8(rest) {
+        match e.error_len() {
+            Some(n) => {
+                count += n;
+                rest = &rest[e.valid_up_to() + n..];
+            }
+            // a sequence that is cut off at the end of the checked part
+            None => break,
+        }
+    }
+    count
+}
+
+/// Check if there is a null byte in the buffer.
+fn has_nul(buf: &[u8]) -> bool {
+    memchr(b'\x00', buf).is_some()
+}
+
 /// Check file for binary-ness.
 ///
-/// Currently only null-bytes are recognized to constitute binary file content.
-/// However, this clashes with UTF-16 and UTF-32, so a more clever heuristic is
-/// required at some point.
+/// A file is binary if it starts with a known magic number, or if its first
+/// part contains a null byte or more than 10% suspicious bytes.  Null bytes
+/// would clash with UTF-16 and UTF-32, but files with a BOM in these encodings
+/// are transcoded to UTF-8 before they get here.
+///
+/// Null bytes after the checked part are detected while searching.
 fn is_binary(buf: &[u8], len: usize) -> bool {
     if len == 0 {
         return false;
@@ -117,101 +300,420 @@ fn is_binary(buf: &[u8], len: usize) -> bool {
         // UTF-8 BOM
         return false;
     }
-    let n = min(512, len);
-    for b in buf[..n].iter() {
-        if *b == b'\x00' {
-            return true;  // null byte always means binary
-        }
+    if BINARY_MAGIC.iter().any(|magic| buf.starts_with(magic)) {
+        return true;
+    }
+    let n = min(BINARY_CHECK_LEN, len);
+    if has_nul(&buf[..n]) {
+        return true;  // null byte always means binary
+    }
+    count_suspicious(&buf[..n]) * 100 / n > 10
+}
+
+/// Count the occurrences of `needle` in the buffer.
+///
+/// This looks at 8 bytes at once, and finds the bytes equal to the needle with
+/// bit operations on the word (which don't give false positives).
+fn count_byte(needle: u8, buf: &[u8]) -> usize {
+    const LO: u64 = 0x0101_0101_0101_0101;
+    const HI7: u64 = 0x7F7F_7F7F_7F7F_7F7F;
+    let pattern = LO * needle as u64;
+    let mut count = 0;
+    let mut rest = buf;
+    while rest.len() >= 8 {
+        let word = unsafe { ptr::read_unaligned(rest.as_ptr() as *const u64) } ^ pattern;
+        // the high bit of each byte is set iff the byte is nonzero
+        let nonzero = ((word & HI7) + HI7) | word;
+        count += (!nonzero & !HI7).count_ones() as usize;
+        rest = &rest[8..];
     }
-    false
+    count + rest.iter().filter(|&&b| b == needle).count()
 }
 
-/// Cache for collecting line offsets and slices within a u8 buffer.
+/// Distance from the last known line above which lines are counted in bulk.
+const LINE_SKIP_DISTANCE: usize = 512;
+
+/// Index of line offsets within a u8 buffer.
+///
+/// Lines include their terminator, which is `eol` (normally a newline).  The
+/// start offsets of all lines that were looked up are kept, sorted so that the
+/// nearest one can be found by binary search, and line numbers are counted
+/// from there.  Nothing is stored for the lines in between.
 struct Lines<'a> {
     buf: &'a [u8],
-    offset: usize,
-    lines: Vec<(usize, &'a [u8])>,
+    eol: u8,
+    /// Known (line number, start offset) pairs, sorted
+    known: Vec<(usize, usize)>,
+    /// Line number, start and end offset of the last line found by offset
+    /// (there are usually more lookups in the same line)
+    cur: (usize, usize, usize),
 }
 
 impl<'a> Lines<'a> {
-    pub fn new(buf: &[u8]) -> Lines {
-        Lines { buf: buf, offset: 0, lines: Vec::with_capacity(100) }
+    pub fn new(buf: &'a [u8], eol: u8) -> Lines<'a> {
+        Lines { buf: buf, eol: eol, known: vec![(0, 0)], cur: (0, 0, 0) }
     }
 
-    /// Advance the line detection until we have at least lineno lines.
-    /// Return false if EOF was reached before given number of lines.
-    fn advance_to_line(&mut self, lineno: usize) -> bool {
-        while self.lines.len() < lineno + 1 {
-            if self.buf.len() == self.offset {
-                return false;
-            }
-            let line = match self.buf[self.offset..].iter().position(|&x| x == b'\n') {
-                Some(idx) => &self.buf[self.offset..self.offset+idx+1],
-                None      => &self.buf[self.offset..self.buf.len()],
-            };
-            self.lines.push((self.offset, line));
-            self.offset += line.len();
+    /// Remember the start offset of a line.
+    fn remember(&mut self, lineno: usize, offset: usize) {
+        let idx = self.last_known(|&(l, _)| l <= lineno);
+        if self.known[idx].0 != lineno {
+            self.known.insert(idx + 1, (lineno, offset));
         }
-        true
     }
 
-    /// Advance to a given byte offset in the buffer.
-    fn advance_to_offset(&mut self, offset: usize) {
-        while self.offset < offset {
-            let next_line = self.lines.len();
-            self.advance_to_line(next_line);
+    /// Find the index of the last known line that satisfies `pred`.
+    fn last_known<F: Fn(&(usize, usize)) -> bool>(&self, pred: F) -> usize {
+        // the common case: looking at the current line or the one after it,
+        // which are among the last few known lines
+        let last = self.known.len() - 1;
+        for idx in (last.saturating_sub(3)..last + 1).rev() {
+            if pred(&self.known[idx]) {
+                return idx;
+            }
+        }
+        // the first known line (line 0) always satisfies pred
+        let (mut lo, mut hi) = (0, last.saturating_sub(3));
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if pred(&self.known[mid]) { lo = mid; } else { hi = mid; }
         }
+        lo
     }
 
     /// Get line number of offset.
     pub fn get_lineno(&mut self, offset: usize) -> usize {
-        self.advance_to_offset(offset);
-        for (n, &(o, _)) in self.lines.iter().enumerate().rev() {
-            if o <= offset {
-                return n;
+        let len = self.buf.len();
+        // the end of the buffer belongs to the last line
+        let offset = if offset == len && len > 0 { len - 1 } else { offset };
+        let (cur, cur_start, cur_end) = self.cur;
+        if cur_start <= offset && offset < cur_end {
+            return cur;
+        }
+        let (mut lineno, mut start) = if cur_start < cur_end && cur_end <= offset {
+            (cur + 1, cur_end)
+        } else {
+            self.known[self.last_known(|&(_, o)| o <= offset)]
+        };
+        // count whole lines at once if they are far away, otherwise it's
+        // faster to go from line to line
+        if offset - start > LINE_SKIP_DISTANCE {
+            let part = &self.buf[start..offset];
+            if let Some(last_eol) = memrchr(self.eol, part) {
+                lineno += count_byte(self.eol, part);
+                start += last_eol + 1;
             }
         }
-        return 0;
+        let end = loop {
+            match memchr(self.eol, &self.buf[start..]) {
+                Some(i) if start + i < offset => {
+                    lineno += 1;
+                    start += i + 1;
+                }
+                Some(i) => break start + i + 1,
+                None => break len,
+            }
+        };
+        self.remember(lineno, start);
+        self.cur = (lineno, start, end);
+        lineno
     }
 
-    /// Get offset of line number.
+    /// Get offset of line number, or the end of the buffer if there is no
+    /// such line.
     pub fn get_offset(&mut self, lineno: usize) -> usize {
-        if self.advance_to_line(lineno) {
-            self.lines[lineno].0
-        } else {
-            self.buf.len()
+        let (cur, cur_start, cur_end) = self.cur;
+        if cur_start < cur_end {
+            if lineno == cur {
+                return cur_start;
+            } else if lineno == cur + 1 {
+                return cur_end;
+            }
+        }
+        let idx = self.last_known(|&(l, _)| l <= lineno);
+        let (mut cur, mut offset) = self.known[idx];
+        if cur == lineno {
+            return offset;
         }
+        match self.known.get(idx + 1) {
+            // the next known line is nearer: go back from there
+            Some(&(next, next_offset)) if next - lineno < lineno - cur => {
+                cur = next;
+                offset = next_offset;
+                while cur > lineno {
+                    offset = memrchr(self.eol, &self.buf[..offset - 1]).map_or(0, |i| i + 1);
+                    cur -= 1;
+                }
+            }
+            _ => {
+                while cur < lineno {
+                    match memchr(self.eol, &self.buf[offset..]) {
+                        Some(i) if offset + i + 1 < self.buf.len() => offset += i + 1,
+                        _ => return self.buf.len(),
+                    }
+                    cur += 1;
+                }
+            }
+        }
+        self.remember(lineno, offset);
+        offset
     }
 
-    /// Get an arbitrary line (maybe beyond end of file) as a string.
-    pub fn get_line(&mut self, lineno: usize) -> Option<Vec<u8>> {
-        if self.advance_to_line(lineno) {
-            Some(self.lines[lineno].1.to_vec())
-        } else {
-            None
+    /// Get an arbitrary line (maybe beyond end of file).
+    pub fn get_line(&mut self, lineno: usize) -> Option<&'a [u8]> {
+        let (cur, cur_start, cur_end) = self.cur;
+        if lineno == cur && cur_start < cur_end {
+            return Some(&self.buf[cur_start..cur_end]);
         }
+        let start = self.get_offset(lineno);
+        if start == self.buf.len() {
+            return None;
+        }
+        let end = memchr(self.eol, &self.buf[start..]).map_or(self.buf.len(),
+                                                              |i| start + i + 1);
+        // lines are often fetched one after another, e.g. for context
+        self.cur = (lineno, start, end);
+        Some(&self.buf[start..end])
     }
 }
 
+/// Return the length of a line without its terminator (which is the NUL byte
+/// for null data, and can be CRLF instead of LF if requested).
+fn content_len(line: &[u8], opts: &Opts) -> usize {
+    if opts.null_data {
+        if line.ends_with(b"\x00") {
+            return line.len() - 1;
+        }
+    } else if opts.crlf && line.ends_with(b"\r\n") {
+        return line.len() - 2;
+    } else if opts.crlf && line.ends_with(b"\n") {
+        return line.len() - 1;
+    }
+    line.len()
+}
+
+/// Prepare a line for output: null data records lose their terminator (a newline
+/// is added on display), and CRLF line endings become LF.
+fn output_line(line: &[u8], opts: &Opts) -> Vec<u8> {
+    let len = content_len(line, opts);
+    let mut result = line[..len].to_vec();
+    if opts.crlf && len < line.len() {
+        result.push(b'\n');
+    }
+    result
+}
+
 /// Create a line-match for a given line with context lines determined by options.
 fn create_match(lines: &mut Lines, opts: &Opts, lineno: usize) -> Match {
     let line = lines.get_line(lineno).expect("matched line missing");
-    let mut new_match = Match::new(lineno + 1, line, vec![]);
+    let mut new_match = Match::new(lineno + 1, output_line(line, opts), vec![]);
+    new_match.offset = lines.get_offset(lineno);
     if opts.before > 0 {
         for lno in lineno.saturating_sub(opts.before)..lineno {
-            new_match.before.push(lines.get_line(lno).unwrap());
+            new_match.before.push(output_line(lines.get_line(lno).unwrap(), opts));
         }
     }
     if opts.after > 0 {
         for lno in lineno+1..lineno+opts.after+1 {
             if let Some(line) = lines.get_line(lno) {
-                new_match.after.push(line);
+                new_match.after.push(output_line(line, opts));
             }
         }
     }
     new_match
 }
 
+/// Finds the matches of a matcher, or of all positive patterns of a query
+/// (if one is given).
+///
+/// For a query, the next match of each pattern is kept, so that a pattern
+/// doesn't search the same text again for every match of another one.
+struct Finder<'a> {
+    matchers: Vec<&'a Matcher>,
+    /// Subject (as address and length) that the kept matches are for
+    subject: (usize, usize),
+    /// Offset each matcher last searched from, and the match it found
+    found: Vec<Option<(usize, Option<(usize, usize)>)>>,
+}
+
+impl<'a> Finder<'a> {
+    pub fn new(regex: &'a Matcher, query: Option<&'a Query>) -> Finder<'a> {
+        let matchers = match query {
+            Some(query) => query.positive(),
+            None => vec![regex],
+        };
+        let n = matchers.len();
+        Finder { matchers: matchers, subject: (0, 0), found: vec![None; n] }
+    }
+
+    /// Find the first match at or after `start`; of several matches at the
+    /// same offset, the longest one is taken.
+    pub fn find_at(&mut self, subject: &[u8], start: usize) -> Option<(usize, usize)> {
+        if self.matchers.len() == 1 {
+            return self.matchers[0].find_at(subject, start);
+        }
+        let key = (subject.as_ptr() as usize, subject.len());
+        if key != self.subject {
+            self.subject = key;
+            for found in &mut self.found {
+                *found = None;
+            }
+        }
+        let mut best: Option<(usize, usize)> = None;
+        for (matcher, found) in self.matchers.iter().zip(&mut self.found) {
+            let next = match *found {
+                // the match found from an earlier offset is still the first
+                // one if it doesn't start before this offset
+                Some((from, next)) if from <= start &&
+                    next.map_or(true, |(s, _)| s >= start) => next,
+                _ => {
+                    let next = matcher.find_at(subject, start);
+                    *found = Some((start, next));
+                    next
+                }
+            };
+            if let Some((s, e)) = next {
+                if best.map_or(true, |(bs, be)| s < bs || (s == bs && e > be)) {
+                    best = next;
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Find the next match in the buffer, starting at `offset`.
+///
+/// In record mode (null data or CRLF line endings), the regex is run on every
+/// record separately, excluding its terminator, so that `^` and `$` anchor at
+/// the record boundaries.
+fn find_match(finder: &mut Finder, opts: &Opts, lines: &mut Lines, buf: &[u8],
+              mut offset: usize) -> Option<(usize, usize)> {
+    if !opts.null_data && !opts.crlf {
+        return finder.find_at(buf, offset);
+    }
+    while offset < buf.len() {
+        let lineno = lines.get_lineno(offset);
+        let rec_start = lines.get_offset(lineno);
+        let rec_end = lines.get_offset(lineno + 1);
+        let content_end = rec_start + content_len(&buf[rec_start..rec_end], opts);
+        if offset <= content_end {
+            let record = &buf[rec_start..content_end];
+            if let Some((start, end)) = finder.find_at(record, offset - rec_start) {
+                return Some((rec_start + start, rec_start + end));
+            }
+        }
+        offset = rec_end;
+    }
+    None
+}
+
+/// Lines with matches of the `--near` pattern, for proximity search.
+///
+/// Matches of the main pattern are only reported if one of these lines is at
+/// most `distance` lines away; those lines are then reported as well.
+struct NearLines {
+    distance: usize,
+    /// Line numbers and spans of all lines with matches
+    lines: Vec<(usize, Vec<(usize, usize)>)>,
+    /// Index of the first line that was neither reported nor skipped
+    next: usize,
+    /// Last line within the distance of the last reported main match
+    reach: Option<usize>,
+    /// Number of reported main matches (lines)
+    reported: usize,
+}
+
+impl NearLines {
+    /// Find all lines with matches of `regex`, like `search` does.
+    fn new(regex: &Matcher, distance: usize, opts: &Opts, lines: &mut Lines,
+           buf: &[u8]) -> NearLines {
+        let mut finder = Finder::new(regex, None);
+        let mut found: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+        let mut offset = 0;
+        while let Some((start, end)) = find_match(&mut finder, opts, lines, buf, offset) {
+            let lineno = lines.get_lineno(start);
+            if lineno != lines.get_lineno(end) {
+                offset = lines.get_offset(lineno + 1);
+                continue;
+            } else if start == end {
+                if start == buf.len() {
+                    break;
+                }
+                offset = lines.get_offset(lineno + 1);
+            } else {
+                offset = end;
+            }
+            let line_offset = lines.get_offset(lineno);
+            let span = (start - line_offset, end - line_offset);
+            match found.last_mut() {
+                Some(&mut (l, ref mut spans)) if l == lineno => spans.push(span),
+                _ => found.push((lineno, vec![span])),
+            }
+        }
+        NearLines { distance: distance, lines: found, next: 0, reach: None, reported: 0 }
+    }
+
+    /// Check if there is a line with matches at most `distance` lines away.
+    fn is_near(&self, lineno: usize) -> bool {
+        let first = lineno.saturating_sub(self.distance);
+        let idx = match self.lines.binary_search_by(|&(l, _)| l.cmp(&first)) {
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.lines.get(idx).map_or(false, |&(l, _)| l <= lineno + self.distance)
+    }
+
+    /// Get the lines to report before a reported main match in `lineno`, and
+    /// the spans in that line itself.
+    fn take_before(&mut self, lineno: usize)
+                   -> (Vec<(usize, Vec<(usize, usize)>)>, Vec<(usize, usize)>) {
+        let mut before = Vec::new();
+        let mut same = Vec::new();
+        while self.next < self.lines.len() && self.lines[self.next].0 <= lineno {
+            let (l, ref spans) = self.lines[self.next];
+            if l == lineno {
+                same = spans.clone();
+            } else if self.reach.map_or(false, |r| l <= r) || l + self.distance >= lineno {
+                before.push((l, spans.clone()));
+            }
+            self.next += 1;
+        }
+        self.reach = Some(lineno + self.distance);
+        self.reported += 1;
+        (before, same)
+    }
+
+    /// Get the lines to report after the last reported main match.
+    fn take_rest(&mut self) -> Vec<(usize, Vec<(usize, usize)>)> {
+        let reach = match self.reach {
+            Some(reach) => reach,
+            None => return Vec::new(),
+        };
+        let rest = self.lines[self.next..].iter().take_while(|&&(l, _)| l <= reach)
+                                                 .cloned().collect();
+        self.next = self.lines.len();
+        rest
+    }
+}
+
+/// Show the lines between the last two matches as context, if they are at
+/// most `distance` lines apart (for proximity search).
+fn bridge_gap(result: &mut FileResult, lines: &mut Lines, opts: &Opts, distance: usize) {
+    let n = result.matches.len();
+    if n < 2 {
+        return;
+    }
+    let next = result.matches[n - 1].lineno;
+    let prev = &mut result.matches[n - 2];
+    if next - prev.lineno > distance {
+        return;
+    }
+    // line numbers of matches start at 1, those of Lines at 0
+    for lno in prev.lineno + prev.after.len()..next - 1 {
+        prev.after.push(output_line(lines.get_line(lno).unwrap(), opts));
+    }
+}
+
 /// Add a new match and maybe finish
 macro_rules! new_match {
     ($result:expr, $lines:expr, $opts:expr, $lineno:expr) => {{
@@ -226,82 +728,495 @@ macro_rules! new_match {
     }};
 }
 
+/// Add a new match with the spans of the `--near` pattern in the line (only
+/// main matches count against --max-count) and maybe finish
+macro_rules! near_match {
+    ($result:expr, $lines:expr, $opts:expr, $near:expr, $lineno:expr, $spans:expr) => {{
+        let mut m = create_match(&mut $lines, $opts, $lineno);
+        m.near = $spans;
+        $result.matches.push(m);
+        if $opts.only_files.is_some() {
+            return $result;
+        }
+        bridge_gap(&mut $result, &mut $lines, $opts, $near.distance);
+    }};
+}
+
+/// Length of a row in hexdump excerpts of matches in binary files.
+pub const HEX_ROW_LEN: usize = 16;
+
+/// Maximum number of rows of a match shown in hexdump excerpts.
+const HEX_MAX_ROWS: usize = 4;
+
+/// Find all matches in a binary file, with an excerpt of the surrounding bytes
+/// (one row before and after the match) as the "line".
+fn find_binary_matches(regex: &Matcher, opts: &Opts, result: &mut FileResult, buf: &[u8]) {
+    let mut offset = 0;
+    while let Some((mut start, mut end)) = regex.find(&buf[offset..]) {
+        start += offset;
+        end += offset;
+        if result.matches.len() >= opts.max_count {
+            return;
+        }
+        let ex_start = (start / HEX_ROW_LEN).saturating_sub(1) * HEX_ROW_LEN;
+        let ex_end = min(end, start + HEX_MAX_ROWS * HEX_ROW_LEN);
+        let ex_end = min(buf.len(), (ex_end / HEX_ROW_LEN + 2) * HEX_ROW_LEN);
+        let mut m = Match::new(0, buf[ex_start..ex_end].to_vec(),
+                               vec![(start - ex_start, min(end, ex_end) - ex_start)]);
+        m.offset = ex_start;
+        result.matches.push(m);
+        if opts.only_files.is_some() {
+            return;
+        }
+        // zero-size matches: continue at the next byte
+        offset = if end > start { end } else { start + 1 };
+        if offset > buf.len() {
+            return;
+        }
+    }
+}
+
+/// Check if the pattern matches anywhere in the buffer, and the buffer
+/// satisfies the query if there is one.
+fn is_match(regex: &Matcher, query: Option<&Query>, buf: &[u8]) -> bool {
+    match query {
+        Some(query) => query.accepts(|m| m.is_match(buf)),
+        None => regex.is_match(buf),
+    }
+}
+
+/// Turn a result into that of a binary file.
+///
+/// Binary files only get a dummy match object, if they matched at all (we never
+/// need more info than "matched" or "didn't match"), unless excerpts of all
+/// matches are requested.  `matched` is true if searching the file as text
+/// already found lines to report (which, with --invert-match, are lines
+/// without a match).
+fn binary_result(mut result: FileResult, regex: &Matcher, query: Option<&Query>,
+                 opts: &Opts, buf: &[u8], matched: bool) -> FileResult {
+    result.is_binary = true;
+    result.matches.clear();
+    // only search it if we care for binaries at all
+    if opts.binaries == Binaries::Report {
+        // excerpts can only show matches, not lines without them
+        if opts.hexdump && !opts.invert {
+            // a query can rule out the file, even if some of its patterns match
+            if query.is_some() && !is_match(regex, query, buf) {
+                return result;
+            }
+            find_binary_matches(regex, opts, &mut result, buf);
+        } else if matched || is_match(regex, query, buf) {
+            result.matches.push(Match::new(0, "".into(), Vec::new()));
+        }
+    }
+    result
+}
+
 /// Search a single file (represented as a u8 buffer) for matching lines.
-pub fn search(regex: &Regex, opts: &Opts, path: &Path, buf: &[u8]) -> FileResult {
+///
+/// If there is a query, `regex` is the query as a matcher (see `create_rx`),
+/// and only files that satisfy the query have matches.
+pub fn search(regex: &Matcher, query: Option<&Query>, opts: &Opts, path: &Path,
+              buf: &[u8]) -> FileResult {
     let len = buf.len();
     let mut result = FileResult::new(normalized_path(path));
     result.has_context = opts.before > 0 || opts.after > 0;
+    result.size = len;
     // binary file?
-    if is_binary(buf, len) {
-        result.is_binary = true;
-        // if we care for binaries at all
-        if opts.do_binaries {
-            if regex.is_match(buf) {
-                // found a match: create a dummy match object, and
-                // leave it there (we never need more info than
-                // "matched" or "didn't match")
-                result.matches.push(Match::new(0, "".into(), Vec::new()));
+    // with null data, NUL bytes are expected in text
+    let check_binary = opts.binaries != Binaries::Text && !opts.null_data;
+    if check_binary && is_binary(buf, len) {
+        return binary_result(result, regex, query, opts, buf, false);
+    }
+    // is_binary() only checks the start of the file, so lines are checked for
+    // null bytes (up to this offset) before we report them, and the rest of
+    // the file once we know there are matches
+    let mut nul_checked = 0;
+    let result = search_lines(result, regex, query, opts, buf, check_binary,
+                              &mut nul_checked);
+    if check_binary && !result.is_binary && !result.matches.is_empty() &&
+        has_nul(&buf[nul_checked..])
+    {
+        return binary_result(result, regex, query, opts, buf, true);
+    }
+    result
+}
+
+/// Search the lines of a text file for matches.
+///
+/// If `check_binary` is true, lines are checked for null bytes before they are
+/// reported.  `nul_checked` is set to the offset up to which this was done.
+fn search_lines(mut result: FileResult, regex: &Matcher, query: Option<&Query>,
+                opts: &Opts, buf: &[u8], check_binary: bool, nul_checked: &mut usize)
+                -> FileResult {
+    let eol = if opts.null_data { b'\x00' } else { b'\n' };
+    let mut lines = Lines::new(buf, eol);
+    // a file that doesn't satisfy the query has no matches at all
+    if let Some(query) = query {
+        let accepted = query.accepts(|m| {
+            find_match(&mut Finder::new(m, None), opts, &mut lines, buf, 0).is_some()
+        });
+        if !accepted {
+            return result;
+        }
+    }
+    let mut finder = Finder::new(regex, query);
+    let mut near_lines = match query.and_then(|q| q.near_matcher()) {
+        // proximity search doesn't make sense for lines without matches
+        Some((distance, m)) if !opts.invert => {
+            let near = NearLines::new(m, distance, opts, &mut lines, buf);
+            // no match can be reported without a near line
+            if near.lines.is_empty() {
+                return result;
             }
+            // matches are grouped with their near lines, like context
+            result.has_context = true;
+            Some(near)
         }
-    } else {
-        let mut lines = Lines::new(buf);
-        let mut match_offset = 0;
-        let mut matched_lineno = !0_usize;  // let's say this is an invalid line number
+        _ => None,
+    };
+    let mut match_offset = 0;
+    let mut matched_lineno = !0_usize;  // let's say this is an invalid line number
 
-        while let Some((mut start, mut end)) = regex.find(&buf[match_offset..]) {
-            // back to offsets into buf
-            start += match_offset;
-            end += match_offset;
+    while let Some((start, end)) = find_match(&mut finder, opts, &mut lines, buf,
+                                              match_offset) {
+        // find the line numbers of the match
+        let lineno = lines.get_lineno(start);
+        let lineno_end = lines.get_lineno(end);
+        if lineno != lineno_end {
+            // match spans multiple lines: ignore it and start at the
+            // beginning of the next line
+            match_offset = lines.get_offset(lineno + 1);
+            continue;
+        } else if start == end {
+            // are we at the end of the text?
+            if start == buf.len() {
+                break;
+            }
+            // zero-size match: match this line and go to next
+            match_offset = lines.get_offset(lineno + 1);
+        } else {
+            // start next match where this one ended
+            match_offset = end;
+        }
 
-            // find the line numbers of the match
-            let lineno = lines.get_lineno(start);
-            let lineno_end = lines.get_lineno(end);
-            if lineno != lineno_end {
-                // match spans multiple lines: ignore it and start at the
-                // beginning of the next line
-                match_offset = lines.get_offset(lineno + 1);
-                continue;
-            } else if start == end {
-                // are we at the end of the text?
-                if start == buf.len() {
-                    break;
-                }
-                // zero-size match: match this line and go to next
-                match_offset = lines.get_offset(lineno + 1);
-            } else {
-                // start next match where this one ended
-                match_offset = end;
+        // a null byte before this line: stop and report as binary
+        if check_binary {
+            let line_end = lines.get_offset(lineno + 1);
+            if has_nul(&buf[*nul_checked..line_end]) {
+                return binary_result(result, regex, query, opts, buf, true);
             }
+            *nul_checked = line_end;
+        }
 
-            if opts.invert {
-                if lineno != matched_lineno {
-                    // create matches for all inbetween lines:
-                    // - matched_lineno is the last one with a match
-                    // - lineno is the one with this match
-                    for inb_lineno in matched_lineno.wrapping_add(1)..lineno {
-                        new_match!(result, lines, opts, inb_lineno);
-                    }
-                    matched_lineno = lineno;
+        if opts.invert {
+            if lineno != matched_lineno {
+                // create matches for all inbetween lines:
+                // - matched_lineno is the last one with a match
+                // - lineno is the one with this match
+                for inb_lineno in matched_lineno.wrapping_add(1)..lineno {
+                    new_match!(result, lines, opts, inb_lineno);
                 }
-            } else {
-                // we have a new matching line?
-                if lineno != matched_lineno {
+                matched_lineno = lineno;
+            }
+        } else {
+            // we have a new matching line?
+            if lineno != matched_lineno {
+                if let Some(ref mut near) = near_lines {
+                    if !near.is_near(lineno) {
+                        continue;
+                    }
+                    if near.reported >= opts.max_count {
+                        break;
+                    }
+                    let (before, same) = near.take_before(lineno);
+                    for (near_lineno, spans) in before {
+                        near_match!(result, lines, opts, near, near_lineno, spans);
+                    }
+                    near_match!(result, lines, opts, near, lineno, same);
+                } else {
                     new_match!(result, lines, opts, lineno);
-                    matched_lineno = lineno;
-                }
-                // add this span to the match for this line
-                if let Some(ref mut m) = result.matches.last_mut() {
-                    let line_offset = lines.get_offset(lineno);
-                    m.spans.push((start - line_offset, end - line_offset));
                 }
+                matched_lineno = lineno;
             }
-        }
-        if opts.invert {
-            // create matches for final lines
-            for inb_lineno in matched_lineno.wrapping_add(1)..lines.get_lineno(buf.len())+1 {
-                new_match!(result, lines, opts, inb_lineno);
+            // add this span to the match for this line
+            if let Some(ref mut m) = result.matches.last_mut() {
+                let line_offset = lines.get_offset(lineno);
+                let (start, end) = (start - line_offset, end - line_offset);
+                // spans of the near pattern are only shown where there is no match
+                m.near.retain(|&(s, e)| (s, e) != (start, end) && (s >= end || e <= start));
+                m.spans.push((start, end));
             }
         }
     }
+    if let Some(ref mut near) = near_lines {
+        for (near_lineno, spans) in near.take_rest() {
+            near_match!(result, lines, opts, near, near_lineno, spans);
+        }
+    }
+    if opts.invert {
+        // create matches for final lines
+        let last_lineno = lines.get_lineno(buf.len());
+        for inb_lineno in matched_lineno.wrapping_add(1)..last_lineno+1 {
+            new_match!(result, lines, opts, inb_lineno);
+        }
+    }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use options::Opts;
+    use matcher::{new_regex, Flags};
+    use super::{count_byte, create_query, create_rx, search, FileResult, Lines, NearLines,
+                LINE_SKIP_DISTANCE};
+
+    #[test]
+    fn count_byte_words() {
+        for len in 0..40 {
+            let buf = (0..len).map(|i| if i % 3 == 0 { b'\n' } else { b'a' + i as u8 })
+                              .collect::<Vec<_>>();
+            let expected = buf.iter().filter(|&&b| b == b'\n').count();
+            assert_eq!(count_byte(b'\n', &buf), expected);
+        }
+        // bytes with the high bit set, and ones just next to the needle
+        assert_eq!(count_byte(b'\n', b"\x8a\x0b\x09\n\xff\n\x00\x80\n"), 3);
+        assert_eq!(count_byte(0, b"\x00\x01\x00\x80\x00\x00\xff\x00\x00"), 6);
+        assert_eq!(count_byte(0xff, b"\xff\xfe\x7f\xff\xff\xff\xff\xff\xff\xff"), 8);
+    }
+
+    #[test]
+    fn lineno() {
+        let buf = b"ab\ncd\n\nef";
+        let mut lines = Lines::new(buf, b'\n');
+        assert_eq!(lines.get_lineno(4), 1);
+        assert_eq!(lines.get_lineno(0), 0);
+        assert_eq!(lines.get_lineno(2), 0);
+        assert_eq!(lines.get_lineno(3), 1);
+        // the start of a line that was not seen yet
+        assert_eq!(lines.get_lineno(6), 2);
+        assert_eq!(lines.get_lineno(7), 3);
+        assert_eq!(lines.get_lineno(buf.len()), 3);
+        assert_eq!(lines.get_lineno(5), 1);
+    }
+
+    #[test]
+    fn lineno_end_of_file() {
+        let mut lines = Lines::new(b"ab\ncd\n", b'\n');
+        assert_eq!(lines.get_lineno(6), 1);
+        let mut lines = Lines::new(b"", b'\n');
+        assert_eq!(lines.get_lineno(0), 0);
+    }
+
+    #[test]
+    fn lineno_far_away() {
+        let buf = b"x\n".iter().cycle().take(20 * LINE_SKIP_DISTANCE).cloned().collect::<Vec<_>>();
+        let mut lines = Lines::new(&buf, b'\n');
+        assert_eq!(lines.get_lineno(4 * LINE_SKIP_DISTANCE + 1), 2 * LINE_SKIP_DISTANCE);
+        assert_eq!(lines.get_lineno(4 * LINE_SKIP_DISTANCE), 2 * LINE_SKIP_DISTANCE);
+        assert_eq!(lines.get_lineno(2 * LINE_SKIP_DISTANCE + 1), LINE_SKIP_DISTANCE);
+        assert_eq!(lines.get_lineno(buf.len() - 1), 10 * LINE_SKIP_DISTANCE - 1);
+        assert_eq!(lines.get_lineno(buf.len()), 10 * LINE_SKIP_DISTANCE - 1);
+        assert_eq!(lines.get_lineno(6), 3);
+    }
+
+    #[test]
+    fn offset() {
+        let buf = b"ab\ncd\n\nef";
+        let mut lines = Lines::new(buf, b'\n');
+        assert_eq!(lines.get_offset(2), 6);
+        assert_eq!(lines.get_offset(0), 0);
+        assert_eq!(lines.get_offset(3), 7);
+        assert_eq!(lines.get_offset(1), 3);
+        // no such lines
+        assert_eq!(lines.get_offset(4), buf.len());
+        assert_eq!(lines.get_offset(100), buf.len());
+        let mut lines = Lines::new(b"ab\n", b'\n');
+        assert_eq!(lines.get_offset(1), 3);
+    }
+
+    #[test]
+    fn offset_backwards() {
+        let buf = b"a\nbb\n\nccc\nd\n";
+        let mut lines = Lines::new(buf, b'\n');
+        // remember line 4, then go back from there
+        assert_eq!(lines.get_lineno(10), 4);
+        assert_eq!(lines.get_offset(3), 6);
+        assert_eq!(lines.get_offset(2), 5);
+        assert_eq!(lines.get_offset(1), 2);
+        assert_eq!(lines.get_offset(4), 10);
+        let mut lines = Lines::new(buf, b'\n');
+        assert_eq!(lines.get_lineno(10), 4);
+        assert_eq!(lines.get_offset(1), 2);
+        assert_eq!(lines.get_offset(0), 0);
+    }
+
+    #[test]
+    fn line() {
+        let buf = b"ab\ncd\n\nef";
+        let mut lines = Lines::new(buf, b'\n');
+        assert_eq!(lines.get_line(1), Some(&b"cd\n"[..]));
+        assert_eq!(lines.get_line(3), Some(&b"ef"[..]));
+        assert_eq!(lines.get_line(2), Some(&b"\n"[..]));
+        assert_eq!(lines.get_line(0), Some(&b"ab\n"[..]));
+        assert_eq!(lines.get_line(4), None);
+        let mut lines = Lines::new(b"ab\n", b'\n');
+        assert_eq!(lines.get_line(1), None);
+        let mut lines = Lines::new(b"", b'\n');
+        assert_eq!(lines.get_line(0), None);
+    }
+
+    #[test]
+    fn line_null_data() {
+        let mut lines = Lines::new(b"ab\ncd\x00ef\x00", b'\x00');
+        assert_eq!(lines.get_lineno(4), 0);
+        assert_eq!(lines.get_line(1), Some(&b"ef\x00"[..]));
+    }
+
+    #[test]
+    fn empty_lines() {
+        // what "^$" finds: the offset just after a newline, on lines that
+        // were not looked at before
+        let buf = b"a\n\nbb\n\n\nc\n";
+        let mut lines = Lines::new(buf, b'\n');
+        let empty = [2, 6, 7].iter().map(|&o| lines.get_lineno(o)).collect::<Vec<_>>();
+        assert_eq!(empty, vec![1, 3, 4]);
+        for &lineno in &empty {
+            assert_eq!(lines.get_line(lineno), Some(&b"\n"[..]));
+        }
+    }
+
+    #[test]
+    fn query_with_matcher() {
+        // the pattern in the options is replaced by the given matcher
+        let mut opts = Opts::new("b");
+        let main = new_regex("a[23]", Flags::default()).unwrap();
+        let (rx, query) = create_query(main, &opts).unwrap();
+        assert!(query.is_none());
+        assert_eq!(rx.find(b"a1 b a2"), Some((5, 7)));
+        opts.not_patterns.push("a1".into());
+        let main = new_regex("a[23]", Flags::default()).unwrap();
+        let (rx, query) = create_query(main, &opts).unwrap();
+        let result = search(&*rx, query.as_ref().map(|q| &**q), &opts, Path::new("f"),
+                            b"a2
+a3
+");
+        assert_eq!(result.matches.len(), 2);
+        let result = search(&*rx, query.as_ref().map(|q| &**q), &opts, Path::new("f"),
+                            b"a1
+a2
+");
+        assert!(result.matches.is_empty());
+    }
+
+    const NEAR_TEXT: &'static [u8] = b"a1\nb\nc\nb\na2\nc\nc\nc\nb\nc\nc\nc\nc\na3\nb\n";
+
+    fn near_lines(pattern: &str, distance: usize) -> NearLines {
+        let opts = Opts::new(pattern);
+        let (rx, _, _) = create_rx(&opts).unwrap();
+        NearLines::new(&*rx, distance, &opts, &mut Lines::new(NEAR_TEXT, b'\n'), NEAR_TEXT)
+    }
+
+    fn near_search(opts: &mut Opts, distance: usize) -> FileResult {
+        opts.near = Some((distance, "b".into()));
+        let (rx, query, _) = create_rx(opts).unwrap();
+        search(&*rx, query.as_ref().map(|q| &**q), opts, Path::new("f"), NEAR_TEXT)
+    }
+
+    /// Line numbers and shown spans of the reported lines
+    fn reported(result: &FileResult) -> Vec<(usize, Vec<(usize, usize)>)> {
+        result.matches.iter().map(|m| (m.lineno, m.all_spans().into_owned())).collect()
+    }
+
+    #[test]
+    fn near_lines_taken() {
+        // "b" is in lines 1, 3, 8 and 14
+        let mut near = near_lines("b", 2);
+        assert!(near.is_near(0));
+        assert!(near.is_near(5));
+        assert!(!near.is_near(11));
+        assert!(near.is_near(12));
+        let (before, same) = near.take_before(0);
+        assert!(before.is_empty() && same.is_empty());
+        // line 1 is within reach of line 0, line 3 within distance of line 4
+        let (before, same) = near.take_before(4);
+        assert_eq!(before, vec![(1, vec![(0, 1)]), (3, vec![(0, 1)])]);
+        assert!(same.is_empty());
+        // line 8 is neither, and line 14 is after the last reported line
+        let (before, _) = near.take_before(13);
+        assert!(before.is_empty());
+        assert_eq!(near.take_rest(), vec![(14, vec![(0, 1)])]);
+        assert_eq!(near.take_rest(), vec![]);
+        assert_eq!(near.reported, 3);
+    }
+
+    #[test]
+    fn near_rest_out_of_reach() {
+        let mut near = near_lines("b", 1);
+        assert!(near.take_rest().is_empty());
+        near.take_before(4);
+        assert!(near.take_rest().is_empty());
+    }
+
+    #[test]
+    fn near_grouping() {
+        let result = near_search(&mut Opts::new("a"), 2);
+        assert!(result.has_context);
+        // a1 and a2 are near a "b", a3 is too
+        assert_eq!(reported(&result), vec![(1, vec![(0, 1)]), (2, vec![(0, 1)]),
+                                           (4, vec![(0, 1)]), (5, vec![(0, 1)]),
+                                           (14, vec![(0, 1)]), (15, vec![(0, 1)])]);
+        // the gaps within the distance are bridged with context lines
+        let after = result.matches.iter().map(|m| m.after.len()).collect::<Vec<_>>();
+        assert_eq!(after, vec![0, 1, 0, 0, 0, 0]);
+        assert_eq!(result.matches[1].after, vec![b"c\n".to_vec()]);
+    }
+
+    #[test]
+    fn near_bridge_gap() {
+        let result = near_search(&mut Opts::new("a2"), 4);
+        // lines 6 to 8 lie between a2 and the "b" in line 9
+        assert_eq!(result.matches[2].after, vec![b"c\n".to_vec(); 3]);
+        let result = near_search(&mut Opts::new("a2"), 3);
+        assert_eq!(reported(&result).iter().map(|r| r.0).collect::<Vec<_>>(), vec![2, 4, 5]);
+        assert!(result.matches[2].after.is_empty());
+    }
+
+    #[test]
+    fn near_context_overlap() {
+        // the after-context of a2 already covers part of the gap
+        let mut opts = Opts::new("a2");
+        opts.after = 1;
+        let result = near_search(&mut opts, 4);
+        assert_eq!(result.matches[2].after, vec![b"c\n".to_vec(); 3]);
+        // and more than the gap: nothing is added
+        opts.after = 5;
+        let result = near_search(&mut opts, 4);
+        assert_eq!(result.matches[2].after.len(), 5);
+        // before-context of a line that is bridged to
+        let mut opts = Opts::new("a2");
+        opts.before = 2;
+        let result = near_search(&mut opts, 4);
+        assert_eq!(result.matches[3].before, vec![b"c\n".to_vec(); 2]);
+        assert_eq!(result.matches[2].after.len(), 3);
+    }
+
+    #[test]
+    fn near_counts() {
+        let mut opts = Opts::new("a");
+        opts.max_count = 1;
+        let result = near_search(&mut opts, 2);
+        // the near line of the first match doesn't count against the limit
+        assert_eq!(reported(&result).iter().map(|r| r.0).collect::<Vec<_>>(), vec![1, 2]);
+        let mut opts = Opts::new("[ab]");
+        opts.max_count = 2;
+        let result = near_search(&mut opts, 1);
+        let counts = result.matches.iter().map(|m| (m.lineno, m.count())).collect::<Vec<_>>();
+        // lines with a "b" are main matches here, the near span is dropped
+        assert_eq!(counts, vec![(1, 1), (2, 1)]);
+        assert_eq!(result.matches[1].spans, vec![(0, 1)]);
+        assert!(result.matches[1].near.is_empty());
+    }
+}
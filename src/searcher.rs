@@ -0,0 +1,490 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::Instant;
+use encoding::EncodingRef;
+use memmap::{Mmap, Protection};
+use scoped_pool::Pool;
+
+use decode;
+use display::DisplayMode;
+use matcher::Matcher;
+use query::Query;
+use search::{self, FileResult};
+use options::{Binaries, Casing, Engine, Opts, Sort};
+use stats::Stats;
+use walk::{self, WalkStats};
+
+/// Sort the list of files to search according to the --sort option.
+fn sort_entries(entries: &mut Vec<PathBuf>, sort: Sort, reverse: bool) {
+    match sort {
+        Sort::Path => entries.sort(),
+        Sort::Modified | Sort::Accessed | Sort::Created => {
+            // query timestamps only once; files without one are sorted first
+            let mut keyed = entries.drain(..).map(|entry| {
+                let time = fs::metadata(&entry).ok().and_then(|md| match sort {
+                    Sort::Modified => md.modified().ok(),
+                    Sort::Accessed => md.accessed().ok(),
+                    _ => md.created().ok(),
+                });
+                (time, entry)
+            }).collect::<Vec<_>>();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.extend(keyed.into_iter().map(|(_, entry)| entry));
+        }
+        Sort::Unordered | Sort::Walk => {}
+    }
+    if reverse {
+        entries.reverse();
+    }
+}
+
+/// Walk a directory (given in Opts) and check all found files.
+///
+/// The channel is used to send result structs to the main thread, which gives
+/// them to the DisplayMode for output.  Results are numbered in the order the
+/// files were dispatched, and an unreadable file gets a None result, so that
+/// the main thread can restore the order.
+///
+/// If `slots` is given, a slot is taken for every dispatched file and released
+/// by the main thread once the result is printed.  This bounds the number of
+/// results that have to be buffered for ordered output.
+///
+/// The thread of this function only goes through the files found by the
+/// (parallel) directory walk, it spawns a number of worker threads in a pool
+/// to grep individual files.  Once `cancel` is set, no more files are searched.
+fn walk(chan: SyncSender<(usize, Option<FileResult>)>, slots: Option<SyncSender<()>>,
+        regex: &Matcher, query: Option<&Query>, opts: &Opts, cancel: &AtomicBool)
+        -> WalkStats {
+    // thread pool for individual file grep worker threads
+    let pool = Pool::new(max(opts.workers - 1, 1));
+    let stats = WalkStats::default();
+
+    pool.scoped(|scope| {
+        let rx = regex;  // borrow for closures
+        let mut seqno = 0;
+        let mut dispatch = |path: PathBuf| {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            if let Some(ref slots) = slots {
+                let _ = slots.send(());
+            }
+            // open and search file in one of the worker threads
+            let ch = chan.clone();
+            let n = seqno;
+            seqno += 1;
+            scope.execute(move || {
+                if cancel.load(Ordering::Relaxed) {
+                    return;
+                }
+                let res = Mmap::open_path(&path, Protection::Read).ok().map(|map| {
+                    let buf = decode::transcode(unsafe { map.as_slice() }, opts.encoding);
+                    search::search(rx, query, &opts, &path, &buf)
+                });
+                let _ = ch.send((n, res));
+            });
+        };
+        if opts.sort == Sort::Unordered || opts.sort == Sort::Walk {
+            walk::walk_files(opts, &stats, cancel, dispatch);
+        } else {
+            // sorting needs the complete list of files first
+            let mut entries = Vec::new();
+            walk::walk_files(opts, &stats, cancel, |path| entries.push(path));
+            sort_entries(&mut entries, opts.sort, opts.sort_reverse);
+            for entry in entries {
+                dispatch(entry);
+            }
+        }
+    });
+    stats
+}
+
+/// Cut the result down to at most `limit` matches, and return the number of
+/// matches that are left.
+///
+/// Lines without spans (inverted matches, binary files) count as one match,
+/// lines only reported for the `--near` pattern as none.  Of a line with more
+/// matches than allowed, only the first spans are kept.  Lines of the near
+/// pattern after the last match are kept if they are within `near_distance`.
+fn take_matches(r: &mut FileResult, limit: usize, near_distance: usize) -> usize {
+    let mut taken = 0;
+    let mut lines = 0;
+    let mut last_lineno = 0;
+    for m in &mut r.matches {
+        let count = if m.is_near_only() { 0 } else { max(m.count(), 1) };
+        if taken == limit {
+            if count > 0 || m.lineno > last_lineno + near_distance {
+                break;
+            }
+        } else if taken + count > limit {
+            m.spans.truncate(limit - taken);
+            taken = limit;
+        } else {
+            taken += count;
+        }
+        if count > 0 {
+            last_lineno = m.lineno;
+        }
+        lines += 1;
+    }
+    r.matches.truncate(lines);
+    taken
+}
+
+/// Maximum number of results waiting to be printed in ordered output.
+const MAX_PENDING_RESULTS: usize = 1000;
+
+/// Searches files for a pattern, like `ru` does, and gives the results to a
+/// DisplayMode.
+///
+/// The search is configured with builder methods, or from an existing Opts:
+///
+/// ```ignore
+/// let stats = try!(Searcher::new("foo").path("src").context(2, 2).run(&mut display));
+/// ```
+pub struct Searcher {
+    opts: Opts,
+    matcher: Option<Arc<Matcher>>,
+    query: Option<Arc<Query>>,
+    /// Matcher for the main pattern, combined with the other patterns when run
+    main: Option<Arc<Matcher>>,
+}
+
+impl Searcher {
+    /// Create a searcher for `pattern` in the current directory, with the
+    /// defaults of `ru` for non-terminal output.
+    pub fn new(pattern: &str) -> Searcher {
+        Searcher { opts: Opts::new(pattern), matcher: None, query: None, main: None }
+    }
+
+    /// Create a searcher with the given options.
+    pub fn from_opts(opts: Opts) -> Searcher {
+        Searcher { opts: opts, matcher: None, query: None, main: None }
+    }
+
+    /// Get the options of this searcher.
+    pub fn opts(&self) -> &Opts {
+        &self.opts
+    }
+
+    /// Set the file or directory to search.
+    pub fn path(mut self, path: &str) -> Searcher {
+        self.opts.path = path.into();
+        self
+    }
+
+    /// Set the case-sensitivity of matching.
+    pub fn casing(mut self, casing: Casing) -> Searcher {
+        self.opts.casing = casing;
+        self
+    }
+
+    /// Search for the pattern as a literal string instead of a regex.
+    pub fn literal(mut self, literal: bool) -> Searcher {
+        self.opts.literal = literal;
+        self
+    }
+
+    /// Only report files that also match `pattern` (can be given multiple times).
+    pub fn and(mut self, pattern: &str) -> Searcher {
+        self.opts.and_patterns.push(pattern.into());
+        self
+    }
+
+    /// Also report files that match `pattern` instead of the main pattern
+    /// (can be given multiple times).
+    pub fn or(mut self, pattern: &str) -> Searcher {
+        self.opts.or_patterns.push(pattern.into());
+        self
+    }
+
+    /// Don't report files that match `pattern` (can be given multiple times).
+    pub fn not(mut self, pattern: &str) -> Searcher {
+        self.opts.not_patterns.push(pattern.into());
+        self
+    }
+
+    /// Only report matches that have a match of `pattern` at most `distance`
+    /// lines away; these are reported as well.
+    pub fn near(mut self, distance: usize, pattern: &str) -> Searcher {
+        self.opts.near = Some((distance, pattern.into()));
+        self
+    }
+
+    /// Select the regex engine, or fuzzy matching.
+    pub fn engine(mut self, engine: Engine) -> Searcher {
+        self.opts.engine = engine;
+        self
+    }
+
+    /// Use an already compiled matcher instead of compiling the pattern when
+    /// the search is run.  The `and`, `or`, `not` and `near` patterns are
+    /// still compiled and used together with it.
+    pub fn matcher(mut self, matcher: Box<Matcher>) -> Searcher {
+        self.main = Some(Arc::from(matcher));
+        self.matcher = None;
+        self.query = None;
+        self
+    }
+
+    /// Use the matcher and query returned by `search::create_rx` instead of
+    /// compiling the patterns when the search is run.
+    pub fn compiled(mut self, matcher: Arc<Matcher>, query: Option<Arc<Query>>) -> Searcher {
+        self.matcher = Some(matcher);
+        self.query = query;
+        self.main = None;
+        self
+    }
+
+    /// Set the PCRE options: UTF-8 mode and the maximum JIT stack size in bytes.
+    pub fn pcre2(mut self, unicode: bool, jit_stack: Option<usize>) -> Searcher {
+        self.opts.pcre2_unicode = unicode;
+        self.opts.pcre2_jit_stack = jit_stack;
+        self
+    }
+
+    /// Report the lines that don't match instead of those that match.
+    pub fn invert(mut self, invert: bool) -> Searcher {
+        self.opts.invert = invert;
+        self
+    }
+
+    /// Set the number of context lines before and after matches.
+    pub fn context(mut self, before: usize, after: usize) -> Searcher {
+        self.opts.before = before;
+        self.opts.after = after;
+        self
+    }
+
+    /// Set the maximum number of matched lines per file.
+    pub fn max_count(mut self, max_count: usize) -> Searcher {
+        self.opts.max_count = max_count;
+        self
+    }
+
+    /// Set the maximum number of reported files and matches overall.
+    pub fn limits(mut self, max_files: usize, max_matches: usize) -> Searcher {
+        self.opts.max_files = max_files;
+        self.opts.max_matches = max_matches;
+        self
+    }
+
+    /// Set how to treat binary files.
+    pub fn binaries(mut self, binaries: Binaries) -> Searcher {
+        self.opts.binaries = binaries;
+        self
+    }
+
+    /// Set the encoding of the files, if they are not UTF-8.
+    pub fn encoding(mut self, encoding: Option<EncodingRef>) -> Searcher {
+        self.opts.encoding = encoding;
+        self
+    }
+
+    /// Set the order of results.
+    pub fn sort(mut self, sort: Sort, reverse: bool) -> Searcher {
+        self.opts.sort = sort;
+        self.opts.sort_reverse = reverse;
+        self
+    }
+
+    /// Set the number of worker threads.
+    pub fn workers(mut self, workers: usize) -> Searcher {
+        self.opts.workers = workers;
+        self
+    }
+
+    /// Set the directory walk options: whether to search hidden files, to
+    /// respect ignore files and to follow symlinks.
+    pub fn walk_options(mut self, hidden: bool, ignores: bool, follow_links: bool) -> Searcher {
+        self.opts.do_hidden = hidden;
+        self.opts.check_ignores = ignores;
+        self.opts.follow_links = follow_links;
+        self
+    }
+
+    /// Run the search and give all results to `display`.
+    ///
+    /// Spawns the walker thread and prints the results.  If printing fails (e.g.
+    /// because the reader of our output has gone away), the walker is stopped and
+    /// the error is returned.  The walker is also stopped once the global limits
+    /// (--max-files and --max-matches) are reached.
+    ///
+    /// Returns the statistics of the search, which are also printed if
+    /// requested in the options.  An invalid pattern is reported as an error of
+    /// kind `InvalidInput`.
+    pub fn run<D: DisplayMode>(&self, display: &mut D) -> io::Result<Stats> {
+        let opts = self.opts.clone();
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let (matcher, query) = match (&self.matcher, &self.main) {
+            (&Some(ref matcher), _) => (matcher.clone(), self.query.clone()),
+            (&None, &Some(ref main)) => {
+                try!(search::create_query(Box::new(main.clone()), &opts).map_err(invalid))
+            }
+            (&None, &None) => {
+                let (matcher, query, _) = try!(search::create_rx(&opts).map_err(invalid));
+                (matcher, query)
+            }
+        };
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let print_stats = opts.stats;
+        let skip_binaries = opts.binaries == Binaries::Skip;
+        let mut files_left = opts.max_files;
+        let mut matches_left = opts.max_matches;
+        let near_distance = opts.near.as_ref().map_or(0, |near| near.0);
+        // with --files-without-matches, the files without matches are reported
+        let report_empty = opts.only_files == Some(false);
+        // The sync_channel has a bound on pending items.  We don't want to
+        // generate results much faster than we can print them.
+        let (w_chan, r_chan) = sync_channel(4 * opts.workers as usize);
+        // Unless results can be printed as they come in, they are kept until all
+        // results before them (in walk order) are printed.  The walker needs a slot
+        // for every file, which is given back once the result is printed.
+        let ordered = opts.sort != Sort::Unordered;
+        let (slot_chan, slot_release) = sync_channel(MAX_PENDING_RESULTS);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let walk_cancel = cancel.clone();
+        let walker = thread::spawn(move || {
+            walk(w_chan, if ordered { Some(slot_chan) } else { None }, &*matcher,
+                 query.as_ref().map(|q| &**q), &opts, &walk_cancel)
+        });
+        // the result is Ok(false) if no more results should be printed
+        let result = {
+            let mut handle_result = |r: Option<FileResult>| -> io::Result<bool> {
+                let mut r = match r {
+                    Some(r) => r,
+                    None => return Ok(true),
+                };
+                if files_left == 0 || matches_left == 0 {
+                    return Ok(false);
+                }
+                // only what is printed counts against the limits
+                if r.matches.is_empty() == report_empty {
+                    matches_left -= take_matches(&mut r, matches_left, near_distance);
+                    files_left -= 1;
+                }
+                stats.add_result(&r, skip_binaries);
+                try!(display.print_result(r));
+                Ok(files_left > 0 && matches_left > 0)
+            };
+            let mut result = Ok(true);
+            if !ordered {
+                while let Ok((_, r)) = r_chan.recv() {
+                    result = handle_result(r);
+                    if result.as_ref().ok() != Some(&true) {
+                        break;
+                    }
+                }
+            } else {
+                let mut pending = BTreeMap::new();
+                let mut next_seqno = 0;
+                'outer: while let Ok((n, r)) = r_chan.recv() {
+                    pending.insert(n, r);
+                    while let Some(r) = pending.remove(&next_seqno) {
+                        result = handle_result(r);
+                        if result.as_ref().ok() != Some(&true) {
+                            break 'outer;
+                        }
+                        next_seqno += 1;
+                        let _ = slot_release.recv();
+                    }
+                }
+            }
+            result
+        };
+        if result.as_ref().ok() != Some(&true) {
+            // stop the walker, and let it and the workers run into closed channels
+            cancel.store(true, Ordering::SeqCst);
+            drop(r_chan);
+            drop(slot_release);
+        }
+        let walk_stats = walker.join().unwrap();
+        try!(result);
+        stats.finish(&walk_stats, start);
+        if print_stats {
+            try!(display.print_stats(&stats));
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use search::{FileResult, Match};
+    use super::take_matches;
+
+    fn result(spans: &[usize]) -> FileResult {
+        FileResult {
+            fname: "f".into(),
+            is_binary: false,
+            has_context: false,
+            size: 0,
+            matches: spans.iter().enumerate().map(|(i, &n)| Match {
+                lineno: i + 1,
+                offset: 0,
+                line: b"x x x\n".to_vec(),
+                spans: (0..n).map(|j| (2 * j, 2 * j + 1)).collect(),
+                near: Vec::new(),
+                before: Vec::new(),
+                after: Vec::new(),
+            }).collect(),
+        }
+    }
+
+    fn spans(r: &FileResult) -> Vec<usize> {
+        r.matches.iter().map(|m| m.spans.len()).collect()
+    }
+
+    #[test]
+    fn limit_matches() {
+        let mut r = result(&[3, 1, 2]);
+        assert_eq!(take_matches(&mut r, 10, 0), 6);
+        assert_eq!(spans(&r), vec![3, 1, 2]);
+        let mut r = result(&[3, 1, 2]);
+        assert_eq!(take_matches(&mut r, 4, 0), 4);
+        assert_eq!(spans(&r), vec![3, 1]);
+        let mut r = result(&[3, 1, 2]);
+        assert_eq!(take_matches(&mut r, 2, 0), 2);
+        assert_eq!(spans(&r), vec![2]);
+        // inverted matches have no spans
+        let mut r = result(&[0, 0, 0]);
+        assert_eq!(take_matches(&mut r, 2, 0), 2);
+        assert_eq!(spans(&r), vec![0, 0]);
+    }
+
+    #[test]
+    fn limit_near_matches() {
+        // lines 2, 4 and 5 are only reported for the near pattern
+        let near_result = || {
+            let mut r = result(&[1, 0, 2, 0, 0]);
+            for &i in &[1, 3, 4] {
+                r.matches[i].near = vec![(0, 1)];
+            }
+            r
+        };
+        let mut r = near_result();
+        assert_eq!(take_matches(&mut r, 1, 1), 1);
+        assert_eq!(spans(&r), vec![1, 0]);
+        let mut r = near_result();
+        assert_eq!(take_matches(&mut r, 2, 1), 2);
+        assert_eq!(spans(&r), vec![1, 0, 1, 0]);
+        assert_eq!(r.matches[3].near, vec![(0, 1)]);
+        let mut r = near_result();
+        // line 5 is too far from line 3
+        assert_eq!(take_matches(&mut r, 3, 1), 3);
+        assert_eq!(spans(&r), vec![1, 0, 2, 0]);
+    }
+}
@@ -0,0 +1,112 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use libc;
+
+use search::FileResult;
+use walk::WalkStats;
+
+/// Statistics about a whole search run, printed with --stats.
+#[derive(Default)]
+pub struct Stats {
+    /// Directory entries looked at by the walker
+    pub walked: usize,
+    /// Entries skipped because they are hidden
+    pub skipped_hidden: usize,
+    /// Entries skipped because of ignore patterns
+    pub skipped_ignored: usize,
+    /// Files skipped because they are binary
+    pub skipped_binary: usize,
+    /// Files searched
+    pub searched: usize,
+    /// Files with at least one match
+    pub matched_files: usize,
+    /// Lines with at least one match
+    pub matched_lines: usize,
+    /// Matches in total
+    pub matches: usize,
+    /// Bytes of all searched files
+    pub bytes: usize,
+    /// Elapsed wall-clock time
+    pub wall_time: Duration,
+    /// Elapsed CPU time (user and system), if available
+    pub cpu_time: Option<Duration>,
+}
+
+/// Get the CPU time used by this process so far.
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let secs = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec) as u64;
+    let micros = (usage.ru_utime.tv_usec + usage.ru_stime.tv_usec) as u32;
+    Some(Duration::new(secs, micros * 1000))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Format a duration as seconds.
+fn secs(d: Duration) -> String {
+    format!("{}.{:03}s", d.as_secs(), d.subsec_nanos() / 1_000_000)
+}
+
+impl Stats {
+    /// Account for the result of a file.  Binary files that were skipped are
+    /// not counted as searched.
+    pub fn add_result(&mut self, res: &FileResult, skipped_binary: bool) {
+        if res.is_binary && skipped_binary {
+            self.skipped_binary += 1;
+            return;
+        }
+        self.searched += 1;
+        self.bytes += res.size;
+        if !res.matches.is_empty() {
+            self.matched_files += 1;
+        }
+        if !res.is_binary {
+            self.matched_lines += res.matches.iter().filter(|m| !m.is_near_only()).count();
+        }
+        self.matches += res.matches.iter().map(|m| m.count()).sum::<usize>();
+    }
+
+    /// Account for the filtering done by the walker, and the elapsed time.
+    pub fn finish(&mut self, walk_stats: &WalkStats, start: Instant) {
+        self.walked = walk_stats.walked.load(Ordering::Relaxed);
+        self.skipped_hidden = walk_stats.hidden.load(Ordering::Relaxed);
+        self.skipped_ignored = walk_stats.ignored.load(Ordering::Relaxed);
+        self.wall_time = start.elapsed();
+        self.cpu_time = cpu_time();
+    }
+
+    /// Write the statistics in human-readable form.
+    pub fn write_to<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        let cpu_time = self.cpu_time.map(secs).unwrap_or_else(|| "n/a".into());
+        write!(out, "\
+paths walked:    {}
+skipped hidden:  {}
+skipped ignored: {}
+skipped binary:  {}
+files searched:  {}
+matched files:   {}
+matched lines:   {}
+total matches:   {}
+bytes searched:  {}
+elapsed time:    {} wall, {} CPU
+", self.walked, self.skipped_hidden, self.skipped_ignored, self.skipped_binary,
+                       self.searched, self.matched_files, self.matched_lines, self.matches,
+                       self.bytes, secs(self.wall_time), cpu_time)
+    }
+}
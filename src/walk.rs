@@ -0,0 +1,401 @@
+// ---------------------------------------------------------------------------------------
+// Ruthenium, an ack-like searcher, (c) 2015 Georg Brandl.
+// Licensed under the MIT license.
+// ---------------------------------------------------------------------------------------
+
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use scoped_pool::Pool;
+
+use ignore::{self, IgnoreChain};
+use options::Opts;
+
+/// Counters for the decisions made while walking.
+#[derive(Default)]
+pub struct WalkStats {
+    /// Directory entries looked at
+    pub walked: AtomicUsize,
+    /// Entries excluded because they are hidden
+    pub hidden: AtomicUsize,
+    /// Entries excluded because of ignore patterns
+    pub ignored: AtomicUsize,
+}
+
+/// Walks a directory tree like `ru` does, skipping hidden and ignored files.
+///
+/// ```ignore
+/// let stats = Walker::new("src").hidden(true).walk(|path| println!("{}", path.display()));
+/// ```
+pub struct Walker {
+    opts: Opts,
+}
+
+impl Walker {
+    /// Create a walker for the given file or directory, with the defaults of `ru`.
+    pub fn new(path: &str) -> Walker {
+        let mut opts = Opts::new("");
+        opts.path = path.into();
+        Walker { opts: opts }
+    }
+
+    /// Create a walker with the path and walk related settings from `opts`.
+    pub fn from_opts(opts: Opts) -> Walker {
+        Walker { opts: opts }
+    }
+
+    /// Include hidden files and directories.
+    pub fn hidden(mut self, hidden: bool) -> Walker {
+        self.opts.do_hidden = hidden;
+        self
+    }
+
+    /// Skip files matched by ignore files (.gitignore etc.).
+    pub fn ignores(mut self, ignores: bool) -> Walker {
+        self.opts.check_ignores = ignores;
+        self
+    }
+
+    /// Follow symbolic links.
+    pub fn follow_links(mut self, follow: bool) -> Walker {
+        self.opts.follow_links = follow;
+        self
+    }
+
+    /// Set the maximum depth of directories to descend into (0 means only
+    /// the immediate children).
+    pub fn max_depth(mut self, depth: usize) -> Walker {
+        self.opts.depth = depth.saturating_add(1);
+        self
+    }
+
+    /// Set the number of threads reading directories.
+    pub fn threads(mut self, threads: usize) -> Walker {
+        self.opts.workers = threads;
+        self
+    }
+
+    /// Call `func` for every file, in the order described for `walk_files`.
+    pub fn walk<F: FnMut(PathBuf)>(&self, func: F) -> WalkStats {
+        let stats = WalkStats::default();
+        walk_files(&self.opts, &stats, &AtomicBool::new(false), func);
+        stats
+    }
+}
+
+/// Maximum number of directory listings that are read ahead of the consumer.
+const MAX_PENDING_LISTINGS: usize = 1000;
+
+/// An entry of a directory listing that passed all filters.
+enum Entry {
+    /// A file to search
+    File(PathBuf),
+    /// A directory, whose listing will arrive on the channel
+    Dir(JobSlot, Receiver<Vec<Entry>>),
+}
+
+/// A directory to read, with everything needed to filter its entries.
+struct DirJob {
+    path: PathBuf,
+    /// Depth of the directory (the root has 0)
+    depth: usize,
+    /// Ignore patterns of this directory and its parents
+    ignores: Option<Arc<IgnoreChain>>,
+    /// Canonical paths of this directory and its parents, to detect symlink
+    /// loops (only if following symlinks)
+    ancestors: Vec<PathBuf>,
+    /// Where to send the listing
+    listing: Sender<Vec<Entry>>,
+}
+
+/// A directory job, shared by the queues and the directory's entry: it is
+/// taken by whoever gets to it first, a reading thread or the consumer.
+type JobSlot = Arc<Mutex<Option<DirJob>>>;
+
+/// Job queues of the directory reading threads.
+///
+/// Every thread takes jobs from the back of its own queue, so that it proceeds
+/// depth-first (which is the order the listings are consumed in), and steals
+/// from the front of other threads' queues when it runs out of jobs.
+///
+/// The threads stop reading ahead when `MAX_PENDING_LISTINGS` listings are
+/// waiting for the consumer.  The consumer reads the directories it needs
+/// itself if no thread has taken them yet, so it never waits for them.
+struct Queues {
+    queues: Vec<Mutex<VecDeque<JobSlot>>>,
+    /// Number of jobs that are queued or being processed
+    pending: Mutex<usize>,
+    /// Notified when a job is queued, or when all jobs are done
+    wakeup: Condvar,
+    /// Number of listings sent, but not received by the consumer yet
+    unconsumed: Mutex<usize>,
+    /// Notified when the consumer receives a listing
+    consumed: Condvar,
+}
+
+impl Queues {
+    fn new(n: usize) -> Queues {
+        Queues {
+            queues: (0..n).map(|_| Mutex::new(VecDeque::new())).collect(),
+            pending: Mutex::new(0),
+            wakeup: Condvar::new(),
+            unconsumed: Mutex::new(0),
+            consumed: Condvar::new(),
+        }
+    }
+
+    fn push(&self, idx: usize, job: JobSlot) {
+        // the queue is changed while holding the lock, so that a waiting
+        // thread can't miss the notification
+        let mut pending = self.pending.lock().unwrap();
+        *pending += 1;
+        self.queues[idx].lock().unwrap().push_back(job);
+        self.wakeup.notify_one();
+    }
+
+    fn pop(&self, idx: usize) -> Option<JobSlot> {
+        if let Some(job) = self.queues[idx].lock().unwrap().pop_back() {
+            return Some(job);
+        }
+        let n = self.queues.len();
+        for other in (1..n).map(|i| (idx + i) % n) {
+            if let Some(job) = self.queues[other].lock().unwrap().pop_front() {
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    fn has_jobs(&self) -> bool {
+        self.queues.iter().any(|queue| !queue.lock().unwrap().is_empty())
+    }
+
+    fn done(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.wakeup.notify_all();
+        }
+    }
+
+    /// Take the job out of its slot (unless that already happened) and read the
+    /// directory, queueing the subdirectories on queue `idx`.
+    fn read(&self, idx: usize, slot: &JobSlot, opts: &Opts, stats: &WalkStats,
+            cancel: &AtomicBool) {
+        let job = slot.lock().unwrap().take();
+        if let Some(job) = job {
+            // once cancelled, the job is dropped, which ends the walk with
+            // empty listings
+            if !cancel.load(Ordering::Relaxed) {
+                *self.unconsumed.lock().unwrap() += 1;
+                read_dir(job, opts, stats, |child| self.push(idx, child));
+            }
+        }
+    }
+
+    /// Read a directory in the consumer's thread, if no reading thread has
+    /// taken it yet.
+    fn read_now(&self, slot: &JobSlot, opts: &Opts, stats: &WalkStats, cancel: &AtomicBool) {
+        // count this as a job, so that the threads keep running for the
+        // subdirectories
+        *self.pending.lock().unwrap() += 1;
+        self.read(0, slot, opts, stats, cancel);
+        self.done();
+    }
+
+    /// Called by the consumer when it has received a listing.
+    fn consume_one(&self) {
+        *self.unconsumed.lock().unwrap() -= 1;
+        self.consumed.notify_all();
+    }
+
+    /// Main loop of a directory reading thread, returns when all jobs are done.
+    fn run(&self, idx: usize, opts: &Opts, stats: &WalkStats, cancel: &AtomicBool) {
+        loop {
+            {
+                let mut unconsumed = self.unconsumed.lock().unwrap();
+                while *unconsumed >= MAX_PENDING_LISTINGS {
+                    unconsumed = self.consumed.wait(unconsumed).unwrap();
+                }
+            }
+            if let Some(slot) = self.pop(idx) {
+                self.read(idx, &slot, opts, stats, cancel);
+                self.done();
+            } else {
+                let mut pending = self.pending.lock().unwrap();
+                while *pending > 0 && !self.has_jobs() {
+                    pending = self.wakeup.wait(pending).unwrap();
+                }
+                if *pending == 0 {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Decide if a directory entry should be excluded from the walk, and count the
+/// decision in `stats`.
+fn is_excluded(path: &Path, ignores: Option<&IgnoreChain>, opts: &Opts,
+               stats: &WalkStats) -> bool {
+    stats.walked.fetch_add(1, Ordering::Relaxed);
+    // weed out hidden files (this is separate from ignored)
+    if let Some(fname) = path.file_name() {
+        if !opts.do_hidden && fname.to_string_lossy().starts_with(".") {
+            stats.hidden.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    }
+    // weed out ignored files and directories (if we return true here for
+    // directories, the contents are pruned from the walk)
+    if let Some(ignores) = ignores {
+        if opts.check_ignores && ignore::match_patterns(path, ignores) {
+            stats.ignored.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    }
+    false
+}
+
+/// Create the job for reading a directory, and the entry for its listing.
+fn dir_entry(path: PathBuf, depth: usize, parent: &Option<Arc<IgnoreChain>>,
+             ancestors: &[PathBuf], opts: &Opts) -> (JobSlot, Entry) {
+    let (tx, rx) = channel();
+    let ignores = if opts.check_ignores {
+        // read ignore patterns specific to this directory
+        Some(IgnoreChain::new(&path, parent.clone()))
+    } else {
+        None
+    };
+    let mut ancestors = ancestors.to_vec();
+    if opts.follow_links {
+        ancestors.extend(fs::canonicalize(&path).ok());
+    }
+    let job = DirJob { path: path, depth: depth, ignores: ignores, ancestors: ancestors,
+                       listing: tx };
+    let slot = Arc::new(Mutex::new(Some(job)));
+    (slot.clone(), Entry::Dir(slot, rx))
+}
+
+/// Read a directory, filter its entries and send the listing (sorted by name,
+/// so that the walk order is deterministic).  Subdirectories are given to
+/// `spawn` to be read next.
+fn read_dir<F: FnMut(JobSlot)>(job: DirJob, opts: &Opts, stats: &WalkStats, mut spawn: F) {
+    let mut children = match fs::read_dir(&job.path) {
+        Ok(rd) => rd.filter_map(|e| e.ok()).collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    children.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    let mut entries = Vec::new();
+    let mut dir_jobs = Vec::new();
+    for child in children {
+        let path = child.path();
+        if is_excluded(&path, job.ignores.as_ref().map(|i| &**i), opts, stats) {
+            continue;
+        }
+        let file_type = if opts.follow_links {
+            fs::metadata(&path).map(|m| m.file_type())
+        } else {
+            child.file_type()
+        };
+        let file_type = match file_type {
+            Ok(ft) => ft,
+            Err(_) => continue,
+        };
+        // only touch normal files
+        if file_type.is_file() {
+            entries.push(Entry::File(path));
+        } else if file_type.is_dir() && job.depth + 1 < opts.depth {
+            if opts.follow_links {
+                // skip symlink loops
+                if let Ok(canonical) = fs::canonicalize(&path) {
+                    if job.ancestors.contains(&canonical) {
+                        continue;
+                    }
+                }
+            }
+            let (dir_job, entry) = dir_entry(path, job.depth + 1, &job.ignores,
+                                             &job.ancestors, opts);
+            dir_jobs.push(dir_job);
+            entries.push(entry);
+        }
+    }
+    let _ = job.listing.send(entries);
+    // the first subdirectory is needed first, and spawned jobs are processed
+    // in reverse order by the current thread
+    for dir_job in dir_jobs.into_iter().rev() {
+        spawn(dir_job);
+    }
+}
+
+/// Go through the listings in depth-first order and call `func` for each file.
+///
+/// A directory that no reading thread has taken yet is read right away.
+fn consume<F: FnMut(PathBuf)>(slot: JobSlot, listing: Receiver<Vec<Entry>>, queues: &Queues,
+                              opts: &Opts, stats: &WalkStats, cancel: &AtomicBool,
+                              func: &mut F) {
+    queues.read_now(&slot, opts, stats, cancel);
+    // the job is dropped without a listing once the walk is cancelled
+    let entries = match listing.recv() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    queues.consume_one();
+    for entry in entries {
+        match entry {
+            Entry::File(path) => func(path),
+            Entry::Dir(slot, listing) => consume(slot, listing, queues, opts, stats, cancel,
+                                                 func),
+        }
+    }
+}
+
+/// Walk the directory given in Opts and call `func` for every file to search.
+///
+/// Files are passed in depth-first order, with directory entries sorted by
+/// name.  The directories are read in parallel (by `opts.workers` threads), in
+/// order to be ready when `func` has gone through the files before them.
+///
+/// The filtering decisions are counted in `stats`.  The walk stops early when
+/// `cancel` is set.
+pub fn walk_files<F: FnMut(PathBuf)>(opts: &Opts, stats: &WalkStats, cancel: &AtomicBool,
+                                     mut func: F) {
+    let root = PathBuf::from(&opts.path);
+    // the root entry is subject to the same filters as all others
+    if is_excluded(&root, None, opts, stats) {
+        return;
+    }
+    // a symlinked root directory is always walked, but unless following links,
+    // the root itself is a symlink and not a file or directory: it is not
+    // searched and has no ignore patterns
+    let root_is_link = !opts.follow_links &&
+        fs::symlink_metadata(&root).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    match fs::metadata(&root) {
+        Ok(ref md) if md.is_file() && !root_is_link => func(root),
+        Ok(ref md) if md.is_dir() && opts.depth > 0 => {
+            // XXX: add global ignores from cmdline and a config file here
+            let (job, entry) = dir_entry(root, 0, &None, &[], opts);
+            if root_is_link {
+                job.lock().unwrap().as_mut().unwrap().ignores = None;
+            }
+            let nthreads = max(opts.workers, 1);
+            let queues = Queues::new(nthreads);
+            queues.push(0, job);
+            let pool = Pool::new(nthreads);
+            pool.scoped(|scope| {
+                let queues = &queues;
+                for idx in 0..nthreads {
+                    scope.execute(move || queues.run(idx, opts, stats, cancel));
+                }
+                if let Entry::Dir(slot, listing) = entry {
+                    consume(slot, listing, queues, opts, stats, cancel, &mut func);
+                }
+            });
+        }
+        _ => {}
+    }
+}
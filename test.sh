@@ -1,31 +1,37 @@
 #!/bin/zsh
 
+# Benchmarks against grep and ag.  "tst" is the tree to search (the needles
+# are picked for a checkout of the Linux kernel sources), the "very many
+# matches" runs search the file that mkbench.sh generates.
+
 export RUST_BACKTRACE=1
 NEEDLE="$1"
 if [ -z "$NEEDLE" ]; then NEEDLE=p.th; fi
+TREE=tst
+MANY=many-matches.txt
 
 run-timed() {
   /usr/bin/time --format="%Us user %Ss system %P%% cpu %e total, max RSS %Mk" "$@"
 }
 
 run-grep() {
-  run-timed grep --color=always -n -E -ri "$@" $NEEDLE tst > /dev/null
+  run-timed grep --color=always -n -E -ri "$@" $NEEDLE $TREE > /dev/null
 }
 
 run-ag() {
-  run-timed ag --color "$@" $NEEDLE tst > /dev/null
+  run-timed ag --color "$@" $NEEDLE $TREE > /dev/null
 }
 
 run-ru-lr() {
-  run-timed target/release/ru-line-regex --color "$@" $NEEDLE tst > /dev/null
+  run-timed target/release/ru-line-regex --color "$@" $NEEDLE $TREE > /dev/null
 }
 
 run-ru-lp() {
-  run-timed target/release/ru-line-pcre --color "$@" $NEEDLE tst > /dev/null
+  run-timed target/release/ru-line-pcre --color "$@" $NEEDLE $TREE > /dev/null
 }
 
 run-ru() {
-  run-timed target/release/ru --color "$@" $NEEDLE tst > /dev/null
+  run-timed target/release/ru --color "$@" $NEEDLE $TREE > /dev/null
 }
 
 run-all() {
@@ -42,6 +48,7 @@ run-all() {
 }
 
 #cargo build --release || exit 1
+[ -f $MANY ] || ./mkbench.sh $MANY
 
 echo "List matches"
 run-all
@@ -54,3 +61,24 @@ run-all -v
 echo
 echo "List files"
 run-all -l
+echo
+echo "Literal"
+NEEDLE=PM_RESUME run-all
+echo
+echo "Case-insensitive literal"
+NEEDLE=pm_resume run-all -i
+echo
+echo "Alternation of literals"
+NEEDLE='ERESTARTSYS|EWOULDBLOCK' run-all
+echo
+echo "Required literal inside a regex"
+NEEDLE='\w+_resume\(' run-all
+echo
+echo "Case-insensitive required literal"
+NEEDLE='[a-z]+_config' run-all -i
+echo
+echo "Very many matches"
+NEEDLE=e TREE=$MANY run-all
+echo
+echo "Count of very many matches"
+NEEDLE=e TREE=$MANY run-all -c